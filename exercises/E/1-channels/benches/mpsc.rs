@@ -0,0 +1,56 @@
+//! Compares the mutex-based mpsc channel with the lock-free one,
+//! with a growing number of threads contending to send
+
+use std::thread;
+
+use channels::mpsc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// The number of messages each sender sends
+const MESSAGES: usize = 10_000;
+
+/// Send `MESSAGES` messages from each of `senders` threads, and receive
+/// them all on the current thread
+macro_rules! run {
+    ($channel:path, $senders:expr) => {{
+        let (tx, mut rx) = $channel();
+        let handles: Vec<_> = (0..$senders)
+            .map(|_| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    // Both channels are unbounded, so sending never fails
+                    // for a lack of room, and both take the same path
+                    for i in 0..MESSAGES {
+                        tx.try_send(i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        let mut received = 0;
+        while rx.recv_blocking().is_some() {
+            received += 1;
+        }
+        assert_eq!(received, $senders * MESSAGES);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }};
+}
+
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mpsc");
+    for senders in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((senders * MESSAGES) as u64));
+        group.bench_with_input(BenchmarkId::new("mutex", senders), &senders, |b, &n| {
+            b.iter(|| run!(mpsc::mutex::channel, n))
+        });
+        group.bench_with_input(BenchmarkId::new("lock-free", senders), &senders, |b, &n| {
+            b.iter(|| run!(mpsc::lock_free::channel, n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+criterion_main!(benches);
@@ -7,11 +7,22 @@ use std::{
 use futures::Stream;
 
 struct Inner<T> {
+    /// The messages not every receiver has seen yet, oldest first
     buffer: VecDeque<T>,
+    /// The maximum number of messages in the buffer. Once it is full,
+    /// the oldest message is dropped, and receivers that did not see it lag.
+    capacity: usize,
+    /// The number of messages removed from the front of the buffer,
+    /// which is the index of the message at its front
     deleted_msg_count: usize,
     txs_left: usize,
     next_rx_id: usize,
-    rxs: HashMap<usize, Option<Waker>>,
+    rxs: HashMap<usize, RxState>,
+}
+
+struct RxState {
+    next_msg_idx: usize, // index into all messages ever sent
+    waker: Option<Waker>,
 }
 
 pub struct Sender<T> {
@@ -20,7 +31,6 @@ pub struct Sender<T> {
 
 pub struct Receiver<T> {
     rx_id: usize,
-    next_msg_idx: usize, // index into `Inner.buffer`
     inner: Arc<Mutex<Inner<T>>>,
 }
 
@@ -29,35 +39,64 @@ pub enum SendError<T> {
     ReceiverDropped(T),
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind, and the given number of messages
+    /// were dropped before it saw them
+    Lagged(u64),
+}
+
 impl<T: Clone> Inner<T> {
     fn get_msg(&self, msg_idx: usize) -> Option<T> {
-        if msg_idx - self.deleted_msg_count >= self.buffer.len() {
-            return None;
-        }
-        Some(self.buffer[msg_idx - self.deleted_msg_count].clone())
+        self.buffer.get(msg_idx - self.deleted_msg_count).cloned()
     }
 }
 
 impl<T> Inner<T> {
     fn set_waker(&mut self, rx_id: usize, waker: Waker) {
-        self.rxs.insert(rx_id, Some(waker));
+        if let Some(rx) = self.rxs.get_mut(&rx_id) {
+            rx.waker = Some(waker);
+        }
     }
 
-    // create a new receiver, returns (new receiver id, next message index)
-    fn new_rx(&mut self) -> (usize, usize) {
+    // create a new receiver starting at `next_msg_idx`, returns its id
+    fn new_rx(&mut self, next_msg_idx: usize) -> usize {
         let rx_id = self.next_rx_id;
         self.next_rx_id += 1;
-        self.rxs.insert(rx_id, None);
-        (rx_id, self.deleted_msg_count)
+        let rx = RxState {
+            next_msg_idx,
+            waker: None,
+        };
+        self.rxs.insert(rx_id, rx);
+        rx_id
     }
 
     fn delete_rx(&mut self, rx_id: usize) {
         self.rxs.remove(&rx_id);
+        self.drop_seen_msgs();
+    }
+
+    /// The index of the next message that will be sent
+    fn end_idx(&self) -> usize {
+        self.deleted_msg_count + self.buffer.len()
+    }
+
+    /// Drop the messages at the front of the buffer every receiver has seen
+    fn drop_seen_msgs(&mut self) {
+        let seen = self
+            .rxs
+            .values()
+            .map(|rx| rx.next_msg_idx)
+            .min()
+            .unwrap_or(self.end_idx());
+        while self.deleted_msg_count < seen && self.buffer.pop_front().is_some() {
+            self.deleted_msg_count += 1;
+        }
     }
 
     fn wake_rxs(&self) {
-        for (_, waker) in self.rxs.iter() {
-            if let Some(waker) = waker {
+        for rx in self.rxs.values() {
+            if let Some(waker) = &rx.waker {
                 waker.wake_by_ref();
             }
         }
@@ -65,36 +104,58 @@ impl<T> Inner<T> {
 }
 
 impl<T> Sender<T> {
-    fn send(&self, value: T) -> Result<(), SendError<T>> {
+    /// Send a message to every receiver. If the buffer is full,
+    /// the oldest message is dropped to make room for it.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
         let mut inner = self.inner.lock().unwrap();
-        if inner.rxs.len() == 0 {
+        if inner.rxs.is_empty() {
             return Err(SendError::ReceiverDropped(value));
         }
 
-        inner.buffer.push_back(value);
-        for (_, waker) in inner.rxs.iter() {
-            if let Some(waker) = waker {
-                waker.wake_by_ref();
-            }
+        if inner.buffer.len() == inner.capacity {
+            inner.buffer.pop_front();
+            inner.deleted_msg_count += 1;
         }
+        inner.buffer.push_back(value);
+        inner.wake_rxs();
         Ok(())
     }
+
+    /// Create a new receiver, which receives the messages sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let next_msg_idx = inner.end_idx();
+        let rx_id = inner.new_rx(next_msg_idx);
+        Receiver {
+            rx_id,
+            inner: self.inner.clone(),
+        }
+    }
 }
 
 impl<T: Clone> Stream for Receiver<T> {
-    type Item = T;
+    type Item = Result<T, RecvError>;
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
         let mut inner = self.inner.lock().unwrap();
+        let deleted_msg_count = inner.deleted_msg_count;
+        let rx = inner.rxs.get_mut(&self.rx_id).unwrap();
+        let next_msg_idx = rx.next_msg_idx;
+
+        // Skip the messages that were dropped before this receiver saw them
+        if next_msg_idx < deleted_msg_count {
+            rx.next_msg_idx = deleted_msg_count;
+            let lagged = (deleted_msg_count - next_msg_idx) as u64;
+            return Poll::Ready(Some(Err(RecvError::Lagged(lagged))));
+        }
 
-        let next_msg = inner.get_msg(self.next_msg_idx);
-        match next_msg {
+        match inner.get_msg(next_msg_idx) {
             Some(v) => {
-                drop(inner); // lifetime hack
-                self.next_msg_idx += 1;
-                Poll::Ready(Some(v))
+                inner.rxs.get_mut(&self.rx_id).unwrap().next_msg_idx += 1;
+                inner.drop_seen_msgs();
+                Poll::Ready(Some(Ok(v)))
             }
             None => {
                 if inner.txs_left == 0 {
@@ -117,12 +178,14 @@ impl<T> Clone for Sender<T> {
     }
 }
 
+/// The clone receives the same messages as the original from now on
 impl<T> Clone for Receiver<T> {
     fn clone(&self) -> Self {
-        let (rx_id, next_msg_idx) = self.inner.lock().unwrap().new_rx();
+        let mut inner = self.inner.lock().unwrap();
+        let next_msg_idx = inner.rxs[&self.rx_id].next_msg_idx;
+        let rx_id = inner.new_rx(next_msg_idx);
         Receiver {
             rx_id,
-            next_msg_idx,
             inner: self.inner.clone(),
         }
     }
@@ -145,13 +208,20 @@ impl<T> Drop for Receiver<T> {
     }
 }
 
-pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+/// Create a new broadcast channel, buffering at most `capacity` messages
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let rx = RxState {
+        next_msg_idx: 0,
+        waker: None,
+    };
     let inner = Arc::new(Mutex::new(Inner {
-        buffer: VecDeque::new(),
+        buffer: VecDeque::with_capacity(capacity),
+        capacity,
         deleted_msg_count: 0,
         next_rx_id: 1,
         txs_left: 1,
-        rxs: HashMap::from([(0, None)]),
+        rxs: HashMap::from([(0, rx)]),
     }));
 
     let tx = Sender {
@@ -159,7 +229,6 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     };
     let rx = Receiver {
         rx_id: 0,
-        next_msg_idx: 0,
         inner: inner.clone(),
     };
     (tx, rx)
@@ -170,35 +239,35 @@ mod tests {
     use futures::{future, StreamExt};
     use tokio::task::{self, JoinHandle};
 
-    use crate::broadcast::{channel, SendError};
+    use crate::broadcast::{channel, RecvError, SendError};
 
     #[tokio::test]
     async fn test_send_recv() {
-        let (tx, mut rx) = channel();
+        let (tx, mut rx) = channel(16);
         for i in 0..3 {
             println!("send #{i}");
             tx.send(i).unwrap();
         }
         for i in 0..3 {
             println!("receive #{i}");
-            assert_eq!(rx.next().await.unwrap(), i);
+            assert_eq!(rx.next().await, Some(Ok(i)));
         }
     }
 
     #[tokio::test]
     async fn test_drop() {
-        let (tx, mut rx) = channel::<()>();
+        let (tx, mut rx) = channel::<()>(16);
         drop(tx);
         assert!(rx.next().await.is_none());
 
-        let (tx, rx) = channel::<()>();
+        let (tx, rx) = channel::<()>(16);
         drop(rx);
         assert!(matches!(tx.send(()), Err(SendError::ReceiverDropped(()))));
     }
 
     #[tokio::test]
     async fn test_multiple_tx_rx() {
-        let (tx, rx) = channel();
+        let (tx, rx) = channel(16);
 
         let mut handles: Vec<JoinHandle<()>> = Vec::new();
         for i in 1..=3 {
@@ -216,13 +285,73 @@ mod tests {
         for i in 0..2 {
             let mut rx = rx.clone();
             handles.push(task::spawn(async move {
+                let mut received = 0;
                 while let Some(msg) = rx.next().await {
-                    println!("rx[#{i}]: {}", msg);
+                    println!("rx[#{i}]: {}", msg.unwrap());
+                    received += 1;
                 }
+                assert_eq!(received, 6);
             }));
         }
         drop(rx);
 
         future::join_all(handles).await;
     }
+
+    #[tokio::test]
+    async fn test_late_subscriber() {
+        let (tx, mut rx) = channel(16);
+        tx.send(1).unwrap();
+        let mut late = tx.subscribe();
+        let mut clone = rx.clone();
+        tx.send(2).unwrap();
+        drop(tx);
+
+        assert_eq!(rx.next().await, Some(Ok(1)));
+        assert_eq!(rx.next().await, Some(Ok(2)));
+        assert_eq!(rx.next().await, None);
+        assert_eq!(late.next().await, Some(Ok(2)));
+        assert_eq!(late.next().await, None);
+        // A clone starts out where the original was
+        assert_eq!(clone.next().await, Some(Ok(1)));
+        assert_eq!(clone.next().await, Some(Ok(2)));
+        assert_eq!(clone.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lagged() {
+        let (tx, mut rx) = channel(2);
+        let mut fast = tx.subscribe();
+        for i in 0..5 {
+            tx.send(i).unwrap();
+            assert_eq!(fast.next().await, Some(Ok(i)));
+        }
+
+        // Only the most recent messages are left for the slow receiver
+        assert_eq!(rx.next().await, Some(Err(RecvError::Lagged(3))));
+        assert_eq!(rx.next().await, Some(Ok(3)));
+        assert_eq!(rx.next().await, Some(Ok(4)));
+        drop(tx);
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_seen_msgs() {
+        let (tx, mut rx) = channel(16);
+        let mut other = rx.clone();
+        let buffered = || tx.inner.lock().unwrap().buffer.len();
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+
+        for _ in 0..3 {
+            rx.next().await.unwrap().unwrap();
+        }
+        assert_eq!(buffered(), 3);
+        other.next().await.unwrap().unwrap();
+        assert_eq!(buffered(), 2);
+        // Receivers that are gone do not hold on to messages
+        drop(other);
+        assert_eq!(buffered(), 0);
+    }
 }
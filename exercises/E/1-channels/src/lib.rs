@@ -1,3 +1,6 @@
+mod blocking;
 pub mod broadcast;
 pub mod mpsc;
 pub mod oneshot;
+pub mod select;
+pub mod watch;
@@ -1,145 +1,103 @@
-use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
-    task::{Context, Poll, Waker},
-};
+#[cfg(feature = "lock-free")]
+mod flavors;
+#[cfg(feature = "lock-free")]
+pub mod lock_free;
+pub mod mutex;
 
-use futures::Stream;
+#[cfg(feature = "lock-free")]
+pub use flavors::{bounded, channel, Receiver, SendFuture, Sender};
+#[cfg(not(feature = "lock-free"))]
+pub use mutex::{bounded, channel, Receiver, SendFuture, Sender};
 
 #[derive(Debug)]
 pub enum SendError<T> {
     ReceiverDropped(T),
 }
 
-pub struct Inner<T> {
-    /// The buffer containing the messages
-    buffer: VecDeque<T>,
-    /// The waker used to wake the Receiver `Future`
-    waker: Option<Waker>,
-    /// Indicates whether the `Receiver` was dropped
-    rx_dropped: bool,
-    /// The number of created `Sender`s that are not yet dropped
-    txs_left: u32,
-}
-
-pub struct Receiver<T> {
-    inner: Arc<Mutex<Inner<T>>>,
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// The buffer of a bounded channel is full
+    Full(T),
+    ReceiverDropped(T),
 }
 
-impl<T> Stream for Receiver<T> {
-    type Item = T;
-
-    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut inner = self.inner.lock().unwrap();
-
-        // todo!("Replace innerwaker with the waker from the context");
-        inner.waker = Some(cx.waker().clone());
-
-        // todo!("Return `Poll::Ready(Some(item))` if there are items in inner.buffer");
-        // todo!("Return `Poll::Pending` if `inner.buffer` is empty");
-        // todo!("Return `Poll::Ready(None)` if all `Sender`s have been dropped");
-        match inner.buffer.pop_front() {
-            Some(item) => Poll::Ready(Some(item)),
-            None => {
-                if inner.txs_left == 0 {
-                    Poll::Ready(None)
-                } else {
-                    Poll::Pending
-                }
-            }
-        }
-    }
+#[derive(Debug)]
+pub enum SendTimeoutError<T> {
+    /// The buffer of a bounded channel stayed full
+    Timeout(T),
+    ReceiverDropped(T),
 }
 
-impl<T> Drop for Receiver<T> {
-    fn drop(&mut self) {
-        let mut inner = self.inner.lock().unwrap();
-        // todo!("Update inner, marking the `Receiver` as dropped")
-        inner.rx_dropped = true;
-    }
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// There are no messages in the buffer
+    Empty,
+    /// The buffer is empty, and all `Sender`s were dropped
+    SendersDropped,
 }
 
-pub struct Sender<T> {
-    inner: Arc<Mutex<Inner<T>>>,
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// No message arrived in time
+    Timeout,
+    /// The buffer is empty, and all `Sender`s were dropped
+    SendersDropped,
 }
 
-impl<T> Sender<T> {
-    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
-        let mut inner = self.inner.lock().unwrap();
-        // todo!("Return `Err(Error::ReceiverDropped(value))` if the `Receiver was dropped`");
-        if inner.rx_dropped {
-            return Err(SendError::ReceiverDropped(value));
-        }
-
-        // todo!("Push `value` to `inner.buffer`");
-        inner.buffer.push_back(value);
-
-        // todo!("Wake inner.waker by reference if it is set");
-        if let Some(waker) = inner.waker.as_ref() {
-            waker.wake_by_ref()
-        }
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        thread,
+        time::Duration,
+    };
 
-        Ok(())
-    }
-}
+    use futures::{
+        task::{noop_waker_ref, waker, ArcWake},
+        FutureExt, StreamExt,
+    };
+    use tokio::task::{self};
 
-impl<T> Clone for Sender<T> {
-    fn clone(&self) -> Self {
-        let inner = self.inner.clone();
-        // todo!("increment the number of `Sender`s left");
-        inner.lock().unwrap().txs_left += 1;
+    use crate::mpsc::{
+        bounded, channel, RecvTimeoutError, SendError, SendFuture, SendTimeoutError, TryRecvError,
+        TrySendError,
+    };
 
-        // todo!("Return a new Sender containing `inner`");
-        Sender { inner }
-    }
-}
+    /// A waker recording whether it was woken
+    #[derive(Default)]
+    struct Flag(AtomicBool);
 
-impl<T> Drop for Sender<T> {
-    fn drop(&mut self) {
-        let mut inner = self.inner.lock().unwrap();
-        // todo!("decrement the number of `Sender`s left");
-        inner.txs_left -= 1;
-        if inner.txs_left > 0 {
-            return;
+    impl ArcWake for Flag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
         }
+    }
 
-        // todo!("Wake inner.waker by reference if it is set");
-        if let Some(waker) = inner.waker.as_ref() {
-            waker.wake_by_ref()
+    impl Flag {
+        /// Whether the waker was woken since the last call
+        fn take(&self) -> bool {
+            self.0.swap(false, Ordering::SeqCst)
         }
     }
-}
-
-/// Create a new mpsc channel
-pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    let inner = Inner {
-        buffer: VecDeque::new(),
-        waker: None,
-        rx_dropped: false,
-        txs_left: 1,
-    };
-    let inner = Arc::new(Mutex::new(inner));
-    let tx = Sender {
-        inner: inner.clone(),
-    };
-    let rx = Receiver { inner };
-    (tx, rx)
-}
-
-#[cfg(test)]
-mod tests {
-    use std::collections::BTreeSet;
-
-    use futures::StreamExt;
-    use tokio::task::{self};
 
-    use crate::mpsc::{channel, SendError};
+    fn poll_send(
+        send: &mut SendFuture<'_, i32>,
+        flag: &Arc<Flag>,
+    ) -> Poll<Result<(), SendError<i32>>> {
+        let waker = waker(flag.clone());
+        send.poll_unpin(&mut Context::from_waker(&waker))
+    }
 
     #[tokio::test]
     async fn test_send_recv() {
         let (tx, mut rx) = channel();
         for i in 0..100 {
-            tx.send(i).unwrap();
+            tx.send(i).await.unwrap();
         }
         for i in 0..100 {
             assert_eq!(rx.next().await.unwrap(), i);
@@ -154,7 +112,16 @@ mod tests {
 
         let (tx, rx) = channel::<()>();
         drop(rx);
-        assert!(matches!(tx.send(()), Err(SendError::ReceiverDropped(()))));
+        assert!(matches!(
+            tx.send(()).await,
+            Err(SendError::ReceiverDropped(()))
+        ));
+
+        // Nothing is sent unless the future is polled
+        let (tx, mut rx) = channel();
+        drop(tx.send(1));
+        drop(tx);
+        assert!(rx.next().await.is_none());
     }
 
     #[tokio::test]
@@ -165,7 +132,7 @@ mod tests {
             task::spawn({
                 let tx = tx.clone();
                 async move {
-                    tx.send(i).unwrap();
+                    tx.send(i).await.unwrap();
                 }
             });
         }
@@ -181,4 +148,137 @@ mod tests {
             .enumerate()
             .for_each(|(i, msg)| assert_eq!(i, msg));
     }
+
+    #[tokio::test]
+    async fn test_try_send() {
+        let (tx, mut rx) = bounded(2);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert!(matches!(tx.try_send(3), Err(TrySendError::Full(3))));
+        assert_eq!(rx.next().await, Some(1));
+        tx.try_send(3).unwrap();
+
+        drop(rx);
+        assert!(matches!(
+            tx.try_send(4),
+            Err(TrySendError::ReceiverDropped(4))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (tx, mut rx) = bounded(1);
+        let producer = task::spawn(async move {
+            for i in 0..100 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(rx.next().await, Some(i));
+        }
+        assert!(rx.next().await.is_none());
+        producer.await.unwrap();
+    }
+
+    #[test]
+    fn test_wakeup_order() {
+        let (tx, mut rx) = bounded(1);
+        let mut rx_cx = Context::from_waker(noop_waker_ref());
+        let flags: Vec<Arc<Flag>> = (0..3).map(|_| Arc::default()).collect();
+        tx.try_send(0).unwrap();
+        let (mut a, mut b, mut c) = (tx.send(1), tx.send(2), tx.send(3));
+        assert!(poll_send(&mut a, &flags[0]).is_pending());
+        assert!(poll_send(&mut b, &flags[1]).is_pending());
+        assert!(poll_send(&mut c, &flags[2]).is_pending());
+
+        // Room in the buffer goes to the sender that waited the longest,
+        // no matter who gets to it first
+        assert_eq!(rx.poll_next_unpin(&mut rx_cx), Poll::Ready(Some(0)));
+        assert!(flags[0].take());
+        assert!(!flags[1].take() && !flags[2].take());
+        assert!(poll_send(&mut b, &flags[1]).is_pending());
+        assert!(matches!(tx.try_send(4), Err(TrySendError::Full(4))));
+        assert!(matches!(poll_send(&mut a, &flags[0]), Poll::Ready(Ok(()))));
+
+        // A sender that gives up waiting passes on its turn
+        assert_eq!(rx.poll_next_unpin(&mut rx_cx), Poll::Ready(Some(1)));
+        assert!(flags[1].take());
+        drop(b);
+        assert!(flags[2].take());
+        assert!(matches!(poll_send(&mut c, &flags[2]), Poll::Ready(Ok(()))));
+        assert_eq!(rx.poll_next_unpin(&mut rx_cx), Poll::Ready(Some(3)));
+    }
+
+    #[test]
+    fn test_drop_while_full() {
+        let (tx, rx) = bounded(1);
+        let flag = Arc::default();
+        tx.try_send(0).unwrap();
+        let mut send = tx.send(1);
+        assert!(poll_send(&mut send, &flag).is_pending());
+
+        drop(rx);
+        assert!(flag.take());
+        assert!(matches!(
+            poll_send(&mut send, &flag),
+            Poll::Ready(Err(SendError::ReceiverDropped(1)))
+        ));
+    }
+
+    #[test]
+    fn test_recv_blocking() {
+        let (tx, mut rx) = bounded(1);
+        let sender = thread::spawn(move || {
+            for i in 0..100 {
+                tx.send_blocking(i).unwrap();
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(rx.recv_blocking(), Some(i));
+        }
+        assert_eq!(rx.recv_blocking(), None);
+        sender.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_to_async() {
+        let (tx, mut rx) = channel();
+        let sender = thread::spawn(move || {
+            for i in 0..10 {
+                tx.send_blocking(i).unwrap();
+            }
+        });
+        for i in 0..10 {
+            assert_eq!(rx.next().await, Some(i));
+        }
+        assert!(rx.next().await.is_none());
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_try_recv_timeout() {
+        let (tx, mut rx) = bounded(1);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        tx.send_timeout(1, Duration::from_millis(10)).unwrap();
+        assert!(matches!(
+            tx.send_timeout(2, Duration::from_millis(10)),
+            Err(SendTimeoutError::Timeout(2))
+        ));
+        // The sender that timed out does not hold up the others
+        assert_eq!(rx.try_recv(), Ok(1));
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)), Ok(3));
+
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::SendersDropped));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::SendersDropped)
+        );
+    }
 }
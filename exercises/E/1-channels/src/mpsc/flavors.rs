@@ -0,0 +1,143 @@
+//! The channel types when the `lock-free` feature is enabled. Unbounded
+//! channels are lock-free, while bounded ones keep using a mutex, as their
+//! `Sender`s have to wait in line for room in the buffer anyway.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{FutureExt, Stream, StreamExt};
+
+use super::{
+    lock_free, mutex, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+
+/// The implementation behind a channel
+enum Flavor<L, M> {
+    LockFree(L),
+    Mutex(M),
+}
+
+/// Call the same method on whichever implementation is behind a channel
+macro_rules! dispatch {
+    ($flavor:expr, $chan:ident => $call:expr) => {
+        match $flavor {
+            Flavor::LockFree($chan) => $call,
+            Flavor::Mutex($chan) => $call,
+        }
+    };
+}
+
+pub struct Receiver<T> {
+    flavor: Flavor<lock_free::Receiver<T>, mutex::Receiver<T>>,
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        dispatch!(&mut self.flavor, rx => rx.poll_next_unpin(cx))
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a message if there is one in the buffer, without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        dispatch!(&mut self.flavor, rx => rx.try_recv())
+    }
+
+    /// Block the current thread until a message arrives.
+    /// Returns `None` once all `Sender`s were dropped.
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        dispatch!(&mut self.flavor, rx => rx.recv_blocking())
+    }
+
+    /// Block the current thread until a message arrives, for at most `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        dispatch!(&mut self.flavor, rx => rx.recv_timeout(timeout))
+    }
+}
+
+pub struct Sender<T> {
+    flavor: Flavor<lock_free::Sender<T>, mutex::Sender<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, waiting for room in the buffer if the channel is bounded.
+    /// `Sender`s waiting for room get it in the order they started waiting.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        let flavor = match &self.flavor {
+            Flavor::LockFree(tx) => Flavor::LockFree(tx.send(value)),
+            Flavor::Mutex(tx) => Flavor::Mutex(tx.send(value)),
+        };
+        SendFuture { flavor }
+    }
+
+    /// Send a message if there is room in the buffer, without waiting
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        dispatch!(&self.flavor, tx => tx.try_send(value))
+    }
+
+    /// Send a message, blocking the current thread while the buffer is full
+    pub fn send_blocking(&self, value: T) -> Result<(), SendError<T>> {
+        dispatch!(&self.flavor, tx => tx.send_blocking(value))
+    }
+
+    /// Send a message, blocking the current thread for at most `timeout`
+    /// while the buffer is full
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        dispatch!(&self.flavor, tx => tx.send_timeout(value, timeout))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let flavor = match &self.flavor {
+            Flavor::LockFree(tx) => Flavor::LockFree(tx.clone()),
+            Flavor::Mutex(tx) => Flavor::Mutex(tx.clone()),
+        };
+        Sender { flavor }
+    }
+}
+
+/// The `Future` returned by [`Sender::send`]
+#[must_use = "futures do nothing unless you .await or poll them"]
+pub struct SendFuture<'a, T> {
+    flavor: Flavor<lock_free::SendFuture<'a, T>, mutex::SendFuture<'a, T>>,
+}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        dispatch!(&mut self.flavor, send => send.poll_unpin(cx))
+    }
+}
+
+/// Create a new lock-free mpsc channel
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = lock_free::channel();
+    let tx = Sender {
+        flavor: Flavor::LockFree(tx),
+    };
+    let rx = Receiver {
+        flavor: Flavor::LockFree(rx),
+    };
+    (tx, rx)
+}
+
+/// Create a new mpsc channel buffering at most `capacity` messages.
+/// Sending waits while the buffer is full.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mutex::bounded(capacity);
+    let tx = Sender {
+        flavor: Flavor::Mutex(tx),
+    };
+    let rx = Receiver {
+        flavor: Flavor::Mutex(rx),
+    };
+    (tx, rx)
+}
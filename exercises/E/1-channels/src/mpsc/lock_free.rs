@@ -0,0 +1,519 @@
+//! An unbounded mpsc channel which does not take any locks, for when many
+//! senders contend for it. With the `lock-free` feature, [`super::channel`]
+//! creates one of these behind the usual `Sender` and `Receiver`.
+//!
+//! Messages are passed through a linked list (a Vyukov queue): senders
+//! swap their node in at the head, and the receiver follows the links
+//! from the tail. The waker of the receiver is kept in an [`AtomicWaker`].
+
+use std::{
+    future::Future,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+
+use super::{RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError};
+use crate::blocking::block_on;
+use sync::{spin_loop, Arc, AtomicBool, AtomicPtr, AtomicUsize, Ordering, UnsafeCell};
+
+#[cfg(not(loom))]
+mod sync {
+    pub use std::{
+        hint::spin_loop,
+        sync::{
+            atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// `std`'s `UnsafeCell`, with the API of loom's
+    pub struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+    impl<T> UnsafeCell<T> {
+        pub fn new(data: T) -> Self {
+            UnsafeCell(std::cell::UnsafeCell::new(data))
+        }
+
+        pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+            f(self.0.get())
+        }
+    }
+}
+
+#[cfg(loom)]
+mod sync {
+    pub use loom::{
+        cell::UnsafeCell,
+        hint::spin_loop,
+        sync::{
+            atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+}
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    /// Empty for the node the list starts out with,
+    /// and for the node the receiver took the message from
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> *mut Self {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value,
+        }))
+    }
+}
+
+struct Queue<T> {
+    /// The node pushed last, which senders replace with theirs
+    head: AtomicPtr<Node<T>>,
+    /// The node whose successor holds the next message.
+    /// Only the receiver touches it.
+    tail: UnsafeCell<*mut Node<T>>,
+}
+
+impl<T> Queue<T> {
+    fn new() -> Self {
+        let stub = Node::new(None);
+        Queue {
+            head: AtomicPtr::new(stub),
+            tail: UnsafeCell::new(stub),
+        }
+    }
+
+    fn push(&self, value: T) {
+        let node = Node::new(Some(value));
+        let prev = self.head.swap(node, Ordering::AcqRel);
+        // SAFETY: the receiver only frees `prev` after moving past it,
+        // which it cannot do before its successor is linked here
+        unsafe { (*prev).next.store(node, Ordering::Release) };
+    }
+
+    /// Take the oldest message. Returns `None` if there is none, or if the
+    /// sender of the next one is not done pushing it yet, in which case it
+    /// wakes the receiver once it is.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may pop at a time.
+    unsafe fn pop(&self) -> Option<T> {
+        self.tail.with_mut(|tail| unsafe {
+            let next = (**tail).next.load(Ordering::Acquire);
+            if next.is_null() {
+                return None;
+            }
+            // Senders only touch the `next` of `next` from now on
+            let value = (*next).value.take();
+            drop(Box::from_raw(*tail));
+            *tail = next;
+            value
+        })
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // Nobody else can reach the nodes anymore
+        let mut node = self.tail.with_mut(|tail| unsafe { *tail });
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next.load(Ordering::Acquire);
+        }
+    }
+}
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 0b01;
+const WAKING: usize = 0b10;
+
+/// Holds the waker of the receiver, which senders wake without taking
+/// a lock. Works like the `AtomicWaker` of the `futures` crate.
+struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    fn new() -> Self {
+        AtomicWaker {
+            state: AtomicUsize::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Replace the waker. Must only be called by the receiver.
+    fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // SAFETY: while REGISTERING, senders leave the waker alone
+                self.waker
+                    .with_mut(|slot| unsafe { *slot = Some(waker.clone()) });
+                let registered = self.state.compare_exchange(
+                    REGISTERING,
+                    WAITING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                if registered.is_err() {
+                    // A sender tried to wake the receiver in the meantime,
+                    // and left that to us
+                    let waker = self.waker.with_mut(|slot| unsafe { (*slot).take() });
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // A sender is busy waking the previous waker, which may be gone
+            // already, so poll again once it is done
+            Err(WAKING) => {
+                spin_loop();
+                waker.wake_by_ref();
+            }
+            Err(_) => unreachable!("only the receiver registers its waker"),
+        }
+    }
+
+    fn wake(&self) {
+        // If another thread holds the waker, it takes care of waking it
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == WAITING {
+            // SAFETY: while WAKING, no one else touches the waker
+            let waker = self.waker.with_mut(|slot| unsafe { (*slot).take() });
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// SAFETY: the waker is only accessed by whoever set the state to
+// REGISTERING or WAKING
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+struct Inner<T> {
+    queue: Queue<T>,
+    /// The waker used to wake the Receiver `Future`
+    waker: AtomicWaker,
+    /// Indicates whether the `Receiver` was dropped
+    rx_dropped: AtomicBool,
+    /// The number of created `Sender`s that are not yet dropped
+    txs_left: AtomicUsize,
+}
+
+// SAFETY: messages are moved from the senders to the receiver, and only the
+// receiver pops them
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn pop(&mut self) -> Option<T> {
+        // SAFETY: there is a single `Receiver`, and it is borrowed mutably
+        unsafe { self.inner.queue.pop() }
+    }
+
+    /// Receive a message if there is one in the buffer, without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(item) = self.pop() {
+            return Ok(item);
+        }
+        // Senders push their messages before they are dropped
+        if self.inner.txs_left.load(Ordering::Acquire) == 0 {
+            return self.pop().ok_or(TryRecvError::SendersDropped);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Block the current thread until a message arrives.
+    /// Returns `None` once all `Sender`s were dropped.
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        block_on(self.next(), None).unwrap()
+    }
+
+    /// Block the current thread until a message arrives, for at most `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match block_on(self.next(), Some(Instant::now() + timeout)) {
+            Some(Some(item)) => Ok(item),
+            Some(None) => Err(RecvTimeoutError::SendersDropped),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(item) = this.pop() {
+            return Poll::Ready(Some(item));
+        }
+        this.inner.waker.register(cx.waker());
+        // A message may have arrived before the waker was registered
+        match this.try_recv() {
+            Ok(item) => Poll::Ready(Some(item)),
+            Err(TryRecvError::SendersDropped) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.rx_dropped.store(true, Ordering::Release);
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message. As the channel is unbounded, this never waits.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            tx: self,
+            value: Some(value),
+        }
+    }
+
+    /// Send a message, which always fits in the buffer
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.send_blocking(value)
+            .map_err(|SendError::ReceiverDropped(value)| TrySendError::ReceiverDropped(value))
+    }
+
+    /// Send a message, which never blocks
+    pub fn send_blocking(&self, value: T) -> Result<(), SendError<T>> {
+        if self.inner.rx_dropped.load(Ordering::Acquire) {
+            return Err(SendError::ReceiverDropped(value));
+        }
+        self.inner.queue.push(value);
+        self.inner.waker.wake();
+        Ok(())
+    }
+
+    /// Send a message, which never times out
+    pub fn send_timeout(&self, value: T, _timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_blocking(value)
+            .map_err(|SendError::ReceiverDropped(value)| SendTimeoutError::ReceiverDropped(value))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.txs_left.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.inner.txs_left.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.inner.waker.wake();
+        }
+    }
+}
+
+/// The `Future` returned by [`Sender::send`], which sends the message
+/// when it is first polled
+#[must_use = "futures do nothing unless you .await or poll them"]
+pub struct SendFuture<'a, T> {
+    tx: &'a Sender<T>,
+    value: Option<T>,
+}
+
+// The message is never pinned
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let value = self
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        Poll::Ready(self.tx.send_blocking(value))
+    }
+}
+
+/// Create a new lock-free mpsc channel
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        queue: Queue::new(),
+        waker: AtomicWaker::new(),
+        rx_dropped: AtomicBool::new(false),
+        txs_left: AtomicUsize::new(1),
+    });
+    let tx = Sender {
+        inner: inner.clone(),
+    };
+    let rx = Receiver { inner };
+    (tx, rx)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{collections::BTreeSet, thread};
+
+    use futures::StreamExt;
+    use tokio::task;
+
+    use crate::mpsc::{lock_free::channel, SendError, TryRecvError};
+
+    #[tokio::test]
+    async fn test_send_recv() {
+        let (tx, mut rx) = channel();
+        for i in 0..100 {
+            tx.send(i).await.unwrap();
+        }
+        for i in 0..100 {
+            assert_eq!(rx.next().await.unwrap(), i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop() {
+        let (tx, mut rx) = channel::<()>();
+        drop(tx);
+        assert!(rx.next().await.is_none());
+
+        let (tx, rx) = channel::<()>();
+        drop(rx);
+        assert!(matches!(
+            tx.send(()).await,
+            Err(SendError::ReceiverDropped(()))
+        ));
+
+        // Messages left behind are dropped along with the channel
+        let (tx, rx) = channel();
+        tx.send(Box::new(1)).await.unwrap();
+        drop(rx);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_tx() {
+        let (tx, mut rx) = channel();
+        for i in 0..10 {
+            task::spawn({
+                let tx = tx.clone();
+                async move {
+                    tx.send(i).await.unwrap();
+                }
+            });
+        }
+        drop(tx);
+        let mut received_msgs = BTreeSet::new();
+        while let Some(msg) = rx.next().await {
+            received_msgs.insert(msg);
+        }
+        assert_eq!(received_msgs, (0..10).collect());
+    }
+
+    #[test]
+    fn test_threads() {
+        let (tx, mut rx) = channel();
+        let senders: Vec<_> = (0..4)
+            .map(|i| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for j in 0..1000 {
+                        tx.send_blocking((i, j)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        // The messages of each sender arrive in order
+        let mut next = [0; 4];
+        while let Some((i, j)) = rx.recv_blocking() {
+            assert_eq!(next[i], j);
+            next[i] += 1;
+        }
+        assert_eq!(next, [1000; 4]);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::SendersDropped));
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use futures::StreamExt;
+    use loom::{future::block_on, thread};
+
+    use crate::mpsc::lock_free::channel;
+
+    #[test]
+    fn test_concurrent_send() {
+        loom::model(|| {
+            let (tx, mut rx) = channel();
+            let senders: Vec<_> = (0..2)
+                .map(|i| {
+                    let tx = tx.clone();
+                    thread::spawn(move || tx.send_blocking(i).unwrap())
+                })
+                .collect();
+            drop(tx);
+
+            let mut received = vec![block_on(rx.next()).unwrap(), block_on(rx.next()).unwrap()];
+            received.sort();
+            assert_eq!(received, [0, 1]);
+            assert_eq!(block_on(rx.next()), None);
+            for sender in senders {
+                sender.join().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_send_while_receiver_dropped() {
+        loom::model(|| {
+            let (tx, rx) = channel();
+            let sender = thread::spawn(move || {
+                let _ = tx.send_blocking(1);
+            });
+            drop(rx);
+            sender.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_in_order() {
+        loom::model(|| {
+            let (tx, mut rx) = channel();
+            let sender = thread::spawn(move || {
+                tx.send_blocking(1).unwrap();
+                tx.send_blocking(2).unwrap();
+            });
+            assert_eq!(block_on(rx.next()), Some(1));
+            assert_eq!(block_on(rx.next()), Some(2));
+            assert_eq!(block_on(rx.next()), None);
+            sender.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,313 @@
+//! The mpsc channel guarding its buffer with a mutex, which [`super::channel`]
+//! and [`super::bounded`] create unless the `lock-free` feature is enabled
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures::{Stream, StreamExt};
+
+use super::{RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError};
+use crate::blocking::block_on;
+
+pub struct Inner<T> {
+    /// The buffer containing the messages
+    buffer: VecDeque<T>,
+    /// The maximum number of messages in the buffer, if the channel is bounded
+    capacity: Option<usize>,
+    /// The waker used to wake the Receiver `Future`
+    waker: Option<Waker>,
+    /// The wakers of the `Sender`s waiting for room in the buffer,
+    /// in the order they started waiting, along with their ids
+    tx_wakers: VecDeque<(u64, Waker)>,
+    /// The id given to the next `Sender` that has to wait
+    next_tx_id: u64,
+    /// Indicates whether the `Receiver` was dropped
+    rx_dropped: bool,
+    /// The number of created `Sender`s that are not yet dropped
+    txs_left: u32,
+}
+
+impl<T> Inner<T> {
+    fn has_room(&self) -> bool {
+        self.capacity
+            .is_none_or(|capacity| self.buffer.len() < capacity)
+    }
+
+    fn push(&mut self, value: T) {
+        self.buffer.push_back(value);
+        if let Some(waker) = self.waker.as_ref() {
+            waker.wake_by_ref()
+        }
+    }
+
+    /// Wake the `Sender` that has been waiting the longest, if there is room for it
+    fn wake_next_tx(&self) {
+        if !self.has_room() {
+            return;
+        }
+        if let Some((_, waker)) = self.tx_wakers.front() {
+            waker.wake_by_ref()
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        // todo!("Replace innerwaker with the waker from the context");
+        inner.waker = Some(cx.waker().clone());
+
+        // todo!("Return `Poll::Ready(Some(item))` if there are items in inner.buffer");
+        // todo!("Return `Poll::Pending` if `inner.buffer` is empty");
+        // todo!("Return `Poll::Ready(None)` if all `Sender`s have been dropped");
+        match inner.buffer.pop_front() {
+            Some(item) => {
+                inner.wake_next_tx();
+                Poll::Ready(Some(item))
+            }
+            None => {
+                if inner.txs_left == 0 {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a message if there is one in the buffer, without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.buffer.pop_front() {
+            Some(item) => {
+                inner.wake_next_tx();
+                Ok(item)
+            }
+            None if inner.txs_left == 0 => Err(TryRecvError::SendersDropped),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Block the current thread until a message arrives.
+    /// Returns `None` once all `Sender`s were dropped.
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        block_on(self.next(), None).unwrap()
+    }
+
+    /// Block the current thread until a message arrives, for at most `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match block_on(self.next(), Some(Instant::now() + timeout)) {
+            Some(Some(item)) => Ok(item),
+            Some(None) => Err(RecvTimeoutError::SendersDropped),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        // todo!("Update inner, marking the `Receiver` as dropped")
+        inner.rx_dropped = true;
+        // Waiting `Sender`s would never get room in the buffer otherwise
+        for (_, waker) in inner.tx_wakers.drain(..) {
+            waker.wake()
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, waiting for room in the buffer if the channel is bounded.
+    /// `Sender`s waiting for room get it in the order they started waiting.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            inner: &self.inner,
+            value: Some(value),
+            id: None,
+        }
+    }
+
+    /// Send a message if there is room in the buffer, without waiting
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rx_dropped {
+            return Err(TrySendError::ReceiverDropped(value));
+        }
+        // Do not jump the queue of `Sender`s waiting for room
+        if !inner.has_room() || !inner.tx_wakers.is_empty() {
+            return Err(TrySendError::Full(value));
+        }
+        inner.push(value);
+        Ok(())
+    }
+
+    /// Send a message, blocking the current thread while the buffer is full
+    pub fn send_blocking(&self, value: T) -> Result<(), SendError<T>> {
+        block_on(self.send(value), None).unwrap()
+    }
+
+    /// Send a message, blocking the current thread for at most `timeout`
+    /// while the buffer is full
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        let mut send = self.send(value);
+        match block_on(&mut send, Some(Instant::now() + timeout)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(SendError::ReceiverDropped(value))) => {
+                Err(SendTimeoutError::ReceiverDropped(value))
+            }
+            None => {
+                let value = send.value.take().unwrap();
+                Err(SendTimeoutError::Timeout(value))
+            }
+        }
+    }
+}
+
+/// The `Future` returned by [`Sender::send`]
+#[must_use = "futures do nothing unless you .await or poll them"]
+pub struct SendFuture<'a, T> {
+    inner: &'a Mutex<Inner<T>>,
+    /// The message, until it is sent
+    value: Option<T>,
+    /// Set while waiting for room in the buffer
+    id: Option<u64>,
+}
+
+// The message is never pinned
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut inner = this.inner.lock().unwrap();
+        let value = this
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        if inner.rx_dropped {
+            inner.tx_wakers.retain(|(id, _)| Some(*id) != this.id);
+            this.id = None;
+            return Poll::Ready(Err(SendError::ReceiverDropped(value)));
+        }
+
+        let first_in_line = match this.id {
+            Some(id) => inner.tx_wakers.front().map(|(first, _)| *first) == Some(id),
+            None => inner.tx_wakers.is_empty(),
+        };
+        if first_in_line && inner.has_room() {
+            if this.id.take().is_some() {
+                inner.tx_wakers.pop_front();
+            }
+            inner.push(value);
+            // There may be room for the next one in line as well
+            inner.wake_next_tx();
+            return Poll::Ready(Ok(()));
+        }
+
+        this.value = Some(value);
+        match this.id {
+            Some(id) => {
+                if let Some((_, waker)) = inner.tx_wakers.iter_mut().find(|(i, _)| *i == id) {
+                    waker.clone_from(cx.waker());
+                }
+            }
+            None => {
+                let id = inner.next_tx_id;
+                inner.next_tx_id += 1;
+                inner.tx_wakers.push_back((id, cx.waker().clone()));
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        // Give up the place in line, passing on the turn if it was ours
+        let mut inner = self.inner.lock().unwrap();
+        inner.tx_wakers.retain(|(i, _)| *i != id);
+        inner.wake_next_tx();
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let inner = self.inner.clone();
+        // todo!("increment the number of `Sender`s left");
+        inner.lock().unwrap().txs_left += 1;
+
+        // todo!("Return a new Sender containing `inner`");
+        Sender { inner }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        // todo!("decrement the number of `Sender`s left");
+        inner.txs_left -= 1;
+        if inner.txs_left > 0 {
+            return;
+        }
+
+        // todo!("Wake inner.waker by reference if it is set");
+        if let Some(waker) = inner.waker.as_ref() {
+            waker.wake_by_ref()
+        }
+    }
+}
+
+/// Create a new mpsc channel
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    with_capacity(None)
+}
+
+/// Create a new mpsc channel buffering at most `capacity` messages.
+/// Sending waits while the buffer is full.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    with_capacity(Some(capacity))
+}
+
+fn with_capacity<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let inner = Inner {
+        buffer: VecDeque::new(),
+        capacity,
+        waker: None,
+        tx_wakers: VecDeque::new(),
+        next_tx_id: 0,
+        rx_dropped: false,
+        txs_left: 1,
+    };
+    let inner = Arc::new(Mutex::new(inner));
+    let tx = Sender {
+        inner: inner.clone(),
+    };
+    let rx = Receiver { inner };
+    (tx, rx)
+}
@@ -1,9 +1,13 @@
 use std::{
     future::Future,
+    pin::Pin,
     sync::{Arc, Mutex},
-    task::{Poll, Waker},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
+use crate::blocking::block_on;
+
 #[derive(Debug)]
 pub enum SendError<T> {
     ReceiverDropped(T),
@@ -14,11 +18,28 @@ pub enum RecvError {
     SenderDropped,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The message was not sent yet
+    Empty,
+    SenderDropped,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// The message was not sent in time
+    Timeout,
+    SenderDropped,
+}
+
 pub struct Inner<T> {
     /// The buffer containing the message.
     data: Option<T>,
     /// The waker used to wake the Receiver `Future`
     waker: Option<Waker>,
+    /// The waker used to wake the `Sender` waiting for the `Receiver`
+    /// to be dropped
+    tx_waker: Option<Waker>,
     /// Indicates whether the `Receiver` was dropped
     rx_dropped: bool,
     /// Indicates whether the `Sender` was dropped
@@ -51,9 +72,39 @@ impl<T> Future for Receiver<T> {
     }
 }
 
+impl<T> Receiver<T> {
+    /// Receive the message if it was sent, without waiting
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.data.take() {
+            Some(v) => Ok(v),
+            None if inner.tx_dropped => Err(TryRecvError::SenderDropped),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Block the current thread until the message arrives
+    pub fn recv_blocking(self) -> Result<T, RecvError> {
+        block_on(self, None).unwrap()
+    }
+
+    /// Block the current thread until the message arrives, for at most `timeout`
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match block_on(self, Some(Instant::now() + timeout)) {
+            Some(Ok(v)) => Ok(v),
+            Some(Err(RecvError::SenderDropped)) => Err(RecvTimeoutError::SenderDropped),
+            None => Err(RecvTimeoutError::Timeout),
+        }
+    }
+}
+
 impl<T> Drop for Receiver<T> {
     fn drop(&mut self) {
-        self.inner.lock().unwrap().rx_dropped = true;
+        let mut inner = self.inner.lock().unwrap();
+        inner.rx_dropped = true;
+        if let Some(waker) = inner.tx_waker.take() {
+            waker.wake()
+        }
     }
 }
 
@@ -79,6 +130,35 @@ impl<T> Sender<T> {
         // > as dropped. Instead std::mem::forget is used to avoid running the destructor.
         // Why???
     }
+
+    /// Indicates whether the `Receiver` was dropped, so sending would fail
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().rx_dropped
+    }
+
+    /// Wait until the `Receiver` is dropped, e.g. to stop working on a
+    /// message no one is waiting for anymore
+    pub fn closed(&mut self) -> Closed<'_, T> {
+        Closed { tx: self }
+    }
+}
+
+/// The `Future` returned by [`Sender::closed`]
+pub struct Closed<'a, T> {
+    tx: &'a mut Sender<T>,
+}
+
+impl<T> Future for Closed<'_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut inner = self.tx.inner.lock().unwrap();
+        if inner.rx_dropped {
+            return Poll::Ready(());
+        }
+        inner.tx_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
 }
 
 impl<T> Drop for Sender<T> {
@@ -96,6 +176,7 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let inner = Inner {
         data: None,
         waker: None,
+        tx_waker: None,
         tx_dropped: false,
         rx_dropped: false,
     };
@@ -109,9 +190,11 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
 
 #[cfg(test)]
 mod tests {
-    use tokio::task;
+    use std::{thread, time::Duration};
+
+    use tokio::{task, time};
 
-    use crate::oneshot::{channel, RecvError, SendError};
+    use crate::oneshot::{channel, RecvError, RecvTimeoutError, SendError, TryRecvError};
 
     #[tokio::test]
     async fn test_send_recv() {
@@ -158,4 +241,55 @@ mod tests {
         println!("after drop tx");
         recv_task.await.unwrap();
     }
+
+    #[test]
+    fn test_recv_blocking() {
+        let (tx, rx) = channel();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.send(123).unwrap();
+        });
+        assert_eq!(rx.recv_blocking().unwrap(), 123);
+        sender.join().unwrap();
+
+        let (tx, rx) = channel::<()>();
+        thread::spawn(move || drop(tx));
+        assert!(matches!(rx.recv_blocking(), Err(RecvError::SenderDropped)));
+    }
+
+    #[test]
+    fn test_try_recv_timeout() {
+        let (tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        tx.send(123).unwrap();
+        assert_eq!(rx.try_recv(), Ok(123));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::SenderDropped));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::SenderDropped)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (mut tx, rx) = channel::<()>();
+        assert!(!tx.is_closed());
+        assert!(time::timeout(Duration::from_millis(10), tx.closed())
+            .await
+            .is_err());
+
+        let closed = task::spawn(async move {
+            tx.closed().await;
+            tx
+        });
+        task::yield_now().await;
+        drop(rx);
+        let tx = closed.await.unwrap();
+        assert!(tx.is_closed());
+        assert!(matches!(tx.send(()), Err(SendError::ReceiverDropped(()))));
+    }
 }
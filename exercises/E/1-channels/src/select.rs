@@ -0,0 +1,384 @@
+//! Waiting for whichever of several receivers, or a timer, fires first
+//!
+//! ```
+//! # use channels::{mpsc, oneshot, select::{sleep, Select}};
+//! # use futures::StreamExt;
+//! # use std::time::Duration;
+//! enum Event {
+//!     Message(Option<u32>),
+//!     Done(Result<(), oneshot::RecvError>),
+//!     Timeout,
+//! }
+//!
+//! # futures::executor::block_on(async {
+//! let (_tx, mut messages) = mpsc::channel::<u32>();
+//! let (_done_tx, mut done) = oneshot::channel::<()>();
+//! let event = Select::new()
+//!     .branch(messages.next(), Event::Message)
+//!     .branch(&mut done, Event::Done)
+//!     .branch(sleep(Duration::from_millis(10)), |()| Event::Timeout)
+//!     .await;
+//! assert!(matches!(event, Event::Timeout));
+//! # });
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap},
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+
+/// A `Future` completing with the output of the first of its branches that
+/// completes. The branches are polled in turns starting from a random one
+/// each time, so a branch that is always ready cannot starve the others.
+pub struct Select<'a, O> {
+    branches: Vec<Pin<Box<dyn Future<Output = O> + 'a>>>,
+}
+
+impl<'a, O> Select<'a, O> {
+    pub fn new() -> Self {
+        Select {
+            branches: Vec::new(),
+        }
+    }
+
+    /// Add a branch waiting for `future`, whose output is turned into the
+    /// output of the `Select` by `map`, telling which branch fired
+    pub fn branch<F, M>(mut self, future: F, map: M) -> Self
+    where
+        F: Future + 'a,
+        M: FnOnce(F::Output) -> O + 'a,
+    {
+        self.branches.push(Box::pin(future.map(map)));
+        self
+    }
+}
+
+impl<O> Default for Select<'_, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<O> Future for Select<'_, O> {
+    type Output = O;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<O> {
+        let n = self.branches.len();
+        assert!(n > 0, "a Select without branches never completes");
+        let start = random() % n;
+        for i in 0..n {
+            let branch = &mut self.branches[(start + i) % n];
+            if let Poll::Ready(output) = branch.as_mut().poll(cx) {
+                return Poll::Ready(output);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// A random number, without depending on a random number generator
+fn random() -> usize {
+    // Every `RandomState` is seeded differently
+    RandomState::new().build_hasher().finish() as usize
+}
+
+/// A timer firing after `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+        id: None,
+    }
+}
+
+/// The `Future` returned by [`sleep`]. Once it is polled, the timer thread
+/// shared by all timers wakes it when the time is up.
+pub struct Sleep {
+    deadline: Instant,
+    /// The id of the timer with the timer thread, once it was polled
+    id: Option<u64>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            if let Some(id) = self.id.take() {
+                timer().cancel(id);
+            }
+            return Poll::Ready(());
+        }
+        let id = timer().schedule(self.id, self.deadline, cx.waker());
+        self.id = Some(id);
+        Poll::Pending
+    }
+}
+
+/// Stop waiting for the timer thread, which would otherwise keep
+/// the waker until the deadline
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            timer().cancel(id);
+        }
+    }
+}
+
+/// The thread waking all timers, which sleeps until the earliest deadline
+struct Timer {
+    timers: Mutex<Timers>,
+    thread: Thread,
+}
+
+#[derive(Default)]
+struct Timers {
+    /// The deadlines of the timers, earliest first. Those of cancelled
+    /// timers are removed lazily.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// The wakers of the timers that are pending
+    wakers: HashMap<u64, Waker>,
+    next_id: u64,
+}
+
+/// The timer thread, which is started when the first timer is polled
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(|| {
+        let thread = thread::Builder::new()
+            .name("timer".to_owned())
+            .spawn(|| timer().run())
+            .expect("cannot start the timer thread");
+        Timer {
+            timers: Mutex::default(),
+            thread: thread.thread().clone(),
+        }
+    })
+}
+
+impl Timer {
+    /// Wake `waker` at `deadline`, for the timer `id` if it was scheduled
+    /// before. Returns the id of the timer.
+    fn schedule(&self, id: Option<u64>, deadline: Instant, waker: &Waker) -> u64 {
+        let mut timers = self.timers.lock().unwrap();
+        if let Some(id) = id {
+            if let Some(current) = timers.wakers.get_mut(&id) {
+                current.clone_from(waker);
+                return id;
+            }
+        }
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.wakers.insert(id, waker.clone());
+        let earliest = timers
+            .deadlines
+            .peek()
+            .is_none_or(|Reverse((earliest, _))| deadline < *earliest);
+        timers.deadlines.push(Reverse((deadline, id)));
+        if earliest {
+            // The thread has to wake up earlier than it planned to
+            self.thread.unpark();
+        }
+        id
+    }
+
+    /// Forget about the timer `id`, which need not be woken anymore
+    fn cancel(&self, id: u64) {
+        let mut timers = self.timers.lock().unwrap();
+        timers.wakers.remove(&id);
+        // Do not let deadlines of cancelled timers pile up
+        if timers.deadlines.len() > 2 * timers.wakers.len() {
+            let Timers {
+                deadlines, wakers, ..
+            } = &mut *timers;
+            deadlines.retain(|Reverse((_, id))| wakers.contains_key(id));
+        }
+    }
+
+    /// Wake the timers whose time is up, and sleep until the next deadline
+    fn run(&self) {
+        loop {
+            let mut expired = Vec::new();
+            let mut timers = self.timers.lock().unwrap();
+            let now = Instant::now();
+            while let Some(&Reverse((deadline, id))) = timers.deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                timers.deadlines.pop();
+                expired.extend(timers.wakers.remove(&id));
+            }
+            let next = timers.deadlines.peek().map(|Reverse((next, _))| *next);
+            drop(timers);
+
+            // Wake without holding the lock, as waking may poll right away
+            for waker in expired {
+                waker.wake();
+            }
+            // Scheduling an earlier timer unparks the thread, also if it
+            // is not parked yet
+            match next {
+                Some(next) => thread::park_timeout(next.saturating_duration_since(now)),
+                None => thread::park(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use futures::StreamExt;
+
+    use crate::{
+        blocking::block_on,
+        mpsc, oneshot,
+        select::{sleep, timer, Select},
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Event {
+        Message(Option<u32>),
+        Done(u32),
+        Timeout,
+    }
+
+    #[test]
+    fn test_which_fired() {
+        let (tx, mut messages) = mpsc::channel();
+        let (done_tx, mut done) = oneshot::channel();
+        let mut select = |timeout| {
+            let select = Select::new()
+                .branch(messages.next(), Event::Message)
+                .branch(&mut done, |res| Event::Done(res.unwrap()))
+                .branch(sleep(timeout), |()| Event::Timeout);
+            block_on(select, None).unwrap()
+        };
+
+        let start = Instant::now();
+        assert_eq!(select(Duration::from_millis(20)), Event::Timeout);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            done_tx.send(7).unwrap();
+        });
+        assert_eq!(select(Duration::from_secs(10)), Event::Done(7));
+        sender.join().unwrap();
+
+        // The oneshot receiver is done, so leave it out from now on
+        let mut select = |timeout| {
+            let select = Select::new()
+                .branch(messages.next(), Event::Message)
+                .branch(sleep(timeout), |()| Event::Timeout);
+            block_on(select, None).unwrap()
+        };
+        tx.send_blocking(1).unwrap();
+        assert_eq!(select(Duration::from_secs(10)), Event::Message(Some(1)));
+        drop(tx);
+        assert_eq!(select(Duration::from_secs(10)), Event::Message(None));
+    }
+
+    /// A channel with `n` messages waiting
+    fn ready(n: u32) -> mpsc::Receiver<u32> {
+        let (tx, rx) = mpsc::channel();
+        for i in 0..n {
+            tx.send_blocking(i).unwrap();
+        }
+        rx
+    }
+
+    /// Both branches fire a fair share of the time, which is 50 times in
+    /// 100 on average. Fewer than 25 are next to impossible by chance.
+    fn assert_fair((a, b): (u32, u32)) {
+        assert!(a >= 25 && b >= 25, "{a} vs {b}");
+    }
+
+    #[test]
+    fn test_fair() {
+        let (mut rx_a, mut rx_b) = (ready(100), ready(100));
+
+        // Both branches are always ready, and get their turns
+        let mut fired = (0, 0);
+        for _ in 0..100 {
+            let select = Select::new()
+                .branch(rx_a.next(), |_| true)
+                .branch(rx_b.next(), |_| false);
+            match block_on(select, None).unwrap() {
+                true => fired.0 += 1,
+                false => fired.1 += 1,
+            }
+        }
+        assert_fair(fired);
+    }
+
+    #[test]
+    fn test_fair_interleaved() {
+        let (mut rx_a, mut rx_b) = (ready(100), ready(100));
+        let (mut rx_c, mut rx_d) = (ready(100), ready(100));
+
+        // Other selects on the same thread do not take away any turns
+        let (mut first, mut second) = ((0, 0), (0, 0));
+        for _ in 0..100 {
+            let select = Select::new()
+                .branch(rx_a.next(), |_| true)
+                .branch(rx_b.next(), |_| false);
+            match block_on(select, None).unwrap() {
+                true => first.0 += 1,
+                false => first.1 += 1,
+            }
+            let select = Select::new()
+                .branch(rx_c.next(), |_| true)
+                .branch(rx_d.next(), |_| false);
+            match block_on(select, None).unwrap() {
+                true => second.0 += 1,
+                false => second.1 += 1,
+            }
+        }
+        assert_fair(first);
+        assert_fair(second);
+    }
+
+    #[test]
+    fn test_sleep() {
+        let start = Instant::now();
+        block_on(sleep(Duration::from_millis(20)), None).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // The timer keeps going when polled by another select
+        let mut timer = sleep(Duration::from_millis(20));
+        let (_tx, mut rx) = mpsc::channel::<()>();
+        let deadline = Some(Instant::now() + Duration::from_millis(5));
+        assert!(block_on(&mut timer, deadline).is_none());
+        let select = Select::new()
+            .branch(rx.next(), |_| false)
+            .branch(&mut timer, |()| true);
+        assert!(block_on(select, None).unwrap());
+    }
+
+    #[test]
+    fn test_sleep_cancel() {
+        let mut sleep = sleep(Duration::from_secs(10));
+        let deadline = Some(Instant::now() + Duration::from_millis(5));
+        assert!(block_on(&mut sleep, deadline).is_none());
+        let id = sleep.id.unwrap();
+        let pending = |id| timer().timers.lock().unwrap().wakers.contains_key(&id);
+        assert!(pending(id));
+
+        // The timer thread forgets about a dropped timer right away
+        drop(sleep);
+        assert!(!pending(id));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug)]
+pub enum SendError<T> {
+    ReceiverDropped(T),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    SenderDropped,
+}
+
+struct Inner<T> {
+    /// The most recent value
+    value: T,
+    /// The number of times the value was replaced
+    version: u64,
+    /// Indicates whether the `Sender` was dropped
+    tx_dropped: bool,
+    next_rx_id: usize,
+    /// The wakers of the receivers, waiting for a change or not
+    rxs: HashMap<usize, Option<Waker>>,
+}
+
+impl<T> Inner<T> {
+    // create a new receiver, returns its id
+    fn new_rx(&mut self) -> usize {
+        let rx_id = self.next_rx_id;
+        self.next_rx_id += 1;
+        self.rxs.insert(rx_id, None);
+        rx_id
+    }
+
+    // replace the value and wake the receivers, returns the previous value
+    fn replace(&mut self, value: T) -> T {
+        let old = std::mem::replace(&mut self.value, value);
+        self.version += 1;
+        self.wake_rxs();
+        old
+    }
+
+    fn wake_rxs(&mut self) {
+        for waker in self.rxs.values_mut() {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+pub struct Receiver<T> {
+    rx_id: usize,
+    /// The version of the value this receiver saw last
+    seen_version: u64,
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+/// A borrowed value, which keeps the `Sender` from replacing it
+/// until it is dropped
+pub struct Ref<'a, T> {
+    inner: MutexGuard<'a, Inner<T>>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.value
+    }
+}
+
+impl<T> Sender<T> {
+    /// Replace the value, unless all receivers were dropped
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rxs.is_empty() {
+            return Err(SendError::ReceiverDropped(value));
+        }
+        inner.replace(value);
+        Ok(())
+    }
+
+    /// Replace the value, even if there are no receivers,
+    /// and return the previous one
+    pub fn send_replace(&self, value: T) -> T {
+        self.inner.lock().unwrap().replace(value)
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            inner: self.inner.lock().unwrap(),
+        }
+    }
+
+    /// Create a new receiver, which sees changes made from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut inner = self.inner.lock().unwrap();
+        Receiver {
+            rx_id: inner.new_rx(),
+            seen_version: inner.version,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Borrow the most recent value, without marking it as seen
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            inner: self.inner.lock().unwrap(),
+        }
+    }
+
+    /// Wait until the value changes. Yields the most recent value and its
+    /// version, skipping any values that were replaced in the meantime.
+    pub fn changed(&mut self) -> Changed<'_, T> {
+        Changed { rx: self }
+    }
+}
+
+/// The `Future` returned by [`Receiver::changed`]
+pub struct Changed<'a, T> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Changed<'_, T> {
+    type Output = Result<(T, u64), RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let rx = &mut *self.rx;
+        let mut inner = rx.inner.lock().unwrap();
+        if inner.version != rx.seen_version {
+            rx.seen_version = inner.version;
+            return Poll::Ready(Ok((inner.value.clone(), inner.version)));
+        }
+        if inner.tx_dropped {
+            return Poll::Ready(Err(RecvError::SenderDropped));
+        }
+        inner.rxs.insert(rx.rx_id, Some(cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+/// The clone has seen the same version as the original
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let rx_id = self.inner.lock().unwrap().new_rx();
+        Receiver {
+            rx_id,
+            seen_version: self.seen_version,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tx_dropped = true;
+        inner.wake_rxs();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().rxs.remove(&self.rx_id);
+    }
+}
+
+/// Create a new watch channel, starting out with `init` as value
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value: init,
+        version: 0,
+        tx_dropped: false,
+        next_rx_id: 1,
+        rxs: HashMap::from([(0, None)]),
+    }));
+
+    let tx = Sender {
+        inner: inner.clone(),
+    };
+    let rx = Receiver {
+        rx_id: 0,
+        seen_version: 0,
+        inner,
+    };
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{task, time};
+
+    use crate::watch::{channel, RecvError, SendError};
+
+    #[tokio::test]
+    async fn test_changed() {
+        let (tx, mut rx) = channel("a");
+        assert_eq!(*rx.borrow(), "a");
+
+        // Only the most recent value is seen
+        assert_eq!(tx.send_replace("b"), "a");
+        assert_eq!(tx.send_replace("c"), "b");
+        assert_eq!(rx.changed().await, Ok(("c", 2)));
+        assert!(time::timeout(Duration::from_millis(10), rx.changed())
+            .await
+            .is_err());
+
+        let changed = task::spawn(async move { rx.changed().await });
+        task::yield_now().await;
+        tx.send("d").unwrap();
+        assert_eq!(changed.await.unwrap(), Ok(("d", 3)));
+        assert_eq!(*tx.borrow(), "d");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let (tx, mut rx) = channel(0);
+        tx.send_replace(1);
+        let mut late = tx.subscribe();
+        let mut clone = rx.clone();
+        tx.send_replace(2);
+
+        assert_eq!(rx.changed().await, Ok((2, 2)));
+        assert_eq!(late.changed().await, Ok((2, 2)));
+        assert_eq!(clone.changed().await, Ok((2, 2)));
+    }
+
+    #[tokio::test]
+    async fn test_drop() {
+        let (tx, mut rx) = channel(0);
+        tx.send_replace(1);
+        drop(tx);
+        // The last change is still seen
+        assert_eq!(rx.changed().await, Ok((1, 1)));
+        assert_eq!(rx.changed().await, Err(RecvError::SenderDropped));
+        assert_eq!(*rx.borrow(), 1);
+
+        let (tx, rx) = channel(0);
+        drop(rx);
+        assert!(matches!(tx.send(1), Err(SendError::ReceiverDropped(1))));
+        assert_eq!(tx.send_replace(2), 0);
+    }
+}
@@ -0,0 +1,98 @@
+//! Credentials users authenticate with.
+//!
+//! The credential store is a text file with a `user:hash` line for every
+//! user, where `hash` is the Argon2 hash of their secret as a PHC string.
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+
+#[derive(Debug)]
+pub struct Credentials {
+    hashes: BTreeMap<String, String>,
+    /// The hash secrets of unknown users are verified against, so that
+    /// checking them takes as long as checking those of known users
+    dummy: String,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials {
+            hashes: BTreeMap::new(),
+            dummy: hash("not the secret of anyone"),
+        }
+    }
+}
+
+/// Hash `secret` with a fresh salt, as a PHC string
+fn hash(secret: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .expect("Argon2 can hash any secret with default parameters")
+        .to_string()
+}
+
+impl Credentials {
+    /// Load the credential store from a file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut credentials = Credentials::default();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid credentials on line {} of {}",
+                        i + 1,
+                        path.display()
+                    ),
+                )
+            };
+            let (user, hash) = line.split_once(':').ok_or_else(invalid)?;
+            PasswordHash::new(hash).map_err(|_| invalid())?;
+            credentials.hashes.insert(user.to_owned(), hash.to_owned());
+        }
+        Ok(credentials)
+    }
+
+    /// Write the credential store to a file
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents: String = self
+            .hashes
+            .iter()
+            .map(|(user, hash)| format!("{user}:{hash}\n"))
+            .collect();
+        fs::write(path, contents)
+    }
+
+    /// Add a user, or change the secret of an existing one
+    pub fn set(&mut self, user: &str, secret: &str) {
+        self.hashes.insert(user.to_owned(), hash(secret));
+    }
+
+    /// Check whether `secret` is the secret of `user`.
+    ///
+    /// Hashing is deliberately slow, so this should not be called
+    /// from an async task directly. It is just as slow for unknown users,
+    /// so the time it takes does not tell which users exist.
+    pub fn verify(&self, user: &str, secret: &str) -> bool {
+        let (hash, known) = match self.hashes.get(user) {
+            Some(hash) => (hash, true),
+            None => (&self.dummy, false),
+        };
+        // The hash was validated when it was loaded
+        let hash = PasswordHash::new(hash).unwrap();
+        let valid = Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok();
+        valid && known
+    }
+}
@@ -0,0 +1,161 @@
+mod tui;
+
+use anyhow::Result;
+use chat::{
+    client::{self, Connector, Event},
+    format::Format,
+    Message,
+};
+use clap::Parser;
+use std::path::PathBuf;
+
+use tokio::{sync::mpsc, task};
+
+/// Chat client
+#[derive(Parser)]
+struct Args {
+    /// Address of the server
+    #[arg(long, default_value = "127.0.0.1:8000")]
+    addr: String,
+    /// PEM file with the CA certificate to verify the server with, enables TLS
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// Name the TLS certificate of the server is verified against
+    #[arg(long, default_value = "localhost")]
+    server_name: String,
+    /// Wire format to propose to the server
+    #[arg(long, default_value_t = Format::Json)]
+    format: Format,
+    /// Secret to authenticate with, if the server requires it
+    #[arg(long, env = "CHAT_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+    /// Show a full-screen terminal UI instead of printing messages line by line
+    #[arg(long)]
+    tui: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut connector = Connector::new(&args.addr, args.format);
+    if let Some(ca) = &args.tls_ca {
+        connector = connector.with_tls(ca, &args.server_name)?;
+    }
+
+    println!("Enter your username and press <enter>");
+    let Some(username) = std::io::stdin().lines().next().transpose()? else {
+        return Ok(());
+    };
+    let handshake = match args.secret.clone() {
+        Some(secret) => Message::Auth {
+            user: username,
+            secret,
+        },
+        None => Message::User(username),
+    };
+
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    let connection = task::spawn(client::run(connector, handshake, outgoing_rx, events_tx));
+    if args.tui {
+        return tui::run(events_rx, outgoing_tx, connection).await;
+    }
+    std::thread::spawn(move || handle_chat_input(outgoing_tx));
+    handle_events(events_rx).await;
+    connection.await?
+}
+
+/// Send the lines entered by the user. Runs on a thread of its own,
+/// as a pending read from stdin would keep the runtime from shutting down.
+fn handle_chat_input(outgoing: mpsc::UnboundedSender<Message>) -> Result<()> {
+    for line in std::io::stdin().lines() {
+        match parse_line(line?) {
+            Ok(msg) => outgoing.send(msg)?,
+            Err(usage) => println!("{usage}"),
+        }
+    }
+    Ok(())
+}
+
+/// Turn a line the user entered into the message to send,
+/// or the usage of a command if its arguments are invalid
+fn parse_line(line: String) -> Result<Message, &'static str> {
+    parse_command(&line).unwrap_or(Ok(Message::ClientMessage(line)))
+}
+
+/// Parse a line starting with a `/` into the message it stands for.
+/// Returns `None` if the line is not a command, and the usage of the
+/// command if its arguments are invalid.
+fn parse_command(line: &str) -> Option<Result<Message, &'static str>> {
+    let line = line.trim();
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim_start();
+    let msg = match command {
+        "/join" => match args.split_whitespace().next() {
+            Some(room) => Ok(Message::Join {
+                room: room.to_owned(),
+            }),
+            None => Err("usage: /join <room>"),
+        },
+        "/leave" => match args.split_whitespace().next() {
+            Some(room) => Ok(Message::Leave {
+                room: room.to_owned(),
+            }),
+            None => Err("usage: /leave <room>"),
+        },
+        "/who" => Ok(Message::ListUsers),
+        "/nick" => match args.split_whitespace().next() {
+            Some(user) => Ok(Message::User(user.to_owned())),
+            None => Err("usage: /nick <username>"),
+        },
+        "/msg" => match args.split_once(char::is_whitespace) {
+            Some((to, content)) => Ok(Message::Direct {
+                to: to.to_owned(),
+                content: content.trim_start().to_owned(),
+            }),
+            None => Err("usage: /msg <user> <text>"),
+        },
+        _ => return None,
+    };
+    Some(msg)
+}
+
+/// Print what happens, until the connection is given up on
+async fn handle_events(mut events: mpsc::UnboundedReceiver<Event>) {
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Message(message) => {
+                if let Some(line) = describe(message) {
+                    println!("{line}");
+                }
+            }
+            Event::Status(status) => println!("{status}"),
+        }
+    }
+}
+
+/// Describe a message received from the server to the user.
+/// Returns `None` for messages that are not worth showing.
+fn describe(message: Message) -> Option<String> {
+    let line = match message {
+        Message::Chat {
+            room,
+            user,
+            content,
+        } => format!("[#{room}] <{user}>: {content}"),
+        Message::DirectChat { user, content } => format!("<{user}> (private): {content}"),
+        Message::Error(error) => format!("ERROR: {error}"),
+        Message::ServerShutdown => "The server is shutting down".to_owned(),
+        Message::Skipped(n) => {
+            format!("({n} messages skipped, you are receiving messages too slowly)")
+        }
+        Message::User(username) => format!("<{username}> joined the chat"),
+        Message::Left(username) => format!("<{username}> left the chat"),
+        Message::Users(users) => format!("Online: {}", users.join(", ")),
+        Message::Rename { from, to } => format!("<{from}> is now known as <{to}>"),
+        Message::Join { room } => format!("You joined #{room}"),
+        Message::Leave { room } => format!("You left #{room}"),
+        _ => return None, // Let's just ignore these
+    };
+    Some(line)
+}
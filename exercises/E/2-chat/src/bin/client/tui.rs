@@ -0,0 +1,313 @@
+//! Full-screen terminal UI, with a scrollable pane of messages,
+//! a sidebar listing the users that are online, and an input line
+//! that is not clobbered by incoming messages
+
+use std::io::{self, Write};
+
+use anyhow::Result;
+use chat::{
+    client::{Event as ConnectionEvent, Status},
+    Message,
+};
+use crossterm::{
+    cursor,
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::Print,
+    terminal::{self, ClearType},
+};
+use futures::StreamExt;
+use tokio::{select, sync::mpsc, task::JoinHandle};
+
+use crate::{describe, parse_line};
+
+/// Width of the sidebar listing the users that are online
+const SIDEBAR_WIDTH: usize = 20;
+
+/// Run the UI until the user quits. Fails with the reason the connection
+/// was given up on, if it was, once the terminal is restored.
+pub async fn run(
+    mut connection: mpsc::UnboundedReceiver<ConnectionEvent>,
+    outgoing: mpsc::UnboundedSender<Message>,
+    mut task: JoinHandle<Result<()>>,
+) -> Result<()> {
+    let _terminal = RawTerminal::enter()?;
+    let mut ui = Ui::new();
+    let mut events = EventStream::new();
+    let mut gave_up = false;
+    let mut failure = None;
+
+    loop {
+        ui.draw(&mut io::stdout())?;
+        select! {
+            event = connection.recv(), if !gave_up => match event {
+                Some(ConnectionEvent::Message(msg)) => ui.receive(msg),
+                Some(ConnectionEvent::Status(status)) => {
+                    if let Status::Connected { .. } = status {
+                        // Fill the sidebar, which may be out of date after reconnecting
+                        let _ = outgoing.send(Message::ListUsers);
+                    }
+                    ui.push(status.to_string());
+                    ui.status = status;
+                }
+                None => {
+                    gave_up = true;
+                    // The task dropped its events, so it is done already
+                    failure = match (&mut task).await {
+                        Ok(result) => result.err(),
+                        Err(err) => Some(err.into()),
+                    };
+                    let reason = match &failure {
+                        Some(err) => format!(" ({err})"),
+                        None => String::new(),
+                    };
+                    ui.push(format!("Cannot enter the chat{reason}, press <esc> to quit"));
+                }
+            },
+            event = events.next() => {
+                let Some(event) = event.transpose()? else {
+                    break;
+                };
+                match ui.handle_event(event) {
+                    Action::None => {}
+                    Action::Quit => break,
+                    Action::Send(line) => match parse_line(line) {
+                        // Sending fails only if the connection was given up on
+                        Ok(msg) => {
+                            if outgoing.send(msg).is_err() {
+                                ui.push("ERROR: not connected".to_owned());
+                            }
+                        }
+                        Err(usage) => ui.push(usage.to_owned()),
+                    },
+                }
+            }
+        }
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+/// Puts the terminal in raw mode on an alternate screen,
+/// and restores it when dropped
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        crossterm::execute!(io::stdout(), terminal::EnterAlternateScreen)?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(io::stdout(), terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// What to do in response to an input event
+enum Action {
+    None,
+    Send(String),
+    Quit,
+}
+
+struct Ui {
+    /// Everything shown in the message pane, oldest first
+    lines: Vec<String>,
+    /// The number of rows the message pane is scrolled up from the bottom
+    scroll: usize,
+    /// The size of the message pane when it was last drawn
+    pane_width: usize,
+    pane_height: usize,
+    /// The users that are online, in alphabetical order
+    users: Vec<String>,
+    /// The line the user is typing
+    input: String,
+    /// The state of the connection to the server
+    status: Status,
+}
+
+impl Ui {
+    fn new() -> Self {
+        Ui {
+            lines: Vec::new(),
+            scroll: 0,
+            pane_width: 0,
+            pane_height: 0,
+            users: Vec::new(),
+            input: String::new(),
+            status: Status::Connecting,
+        }
+    }
+
+    fn receive(&mut self, msg: Message) {
+        match &msg {
+            Message::Users(users) => {
+                // Shown in the sidebar instead
+                self.users = users.clone();
+                return;
+            }
+            Message::User(user) => {
+                if let Err(i) = self.users.binary_search(user) {
+                    self.users.insert(i, user.clone());
+                }
+            }
+            Message::Left(user) => self.users.retain(|u| u != user),
+            Message::Rename { from, to } => {
+                self.users.retain(|u| u != from);
+                if let Err(i) = self.users.binary_search(to) {
+                    self.users.insert(i, to.clone());
+                }
+            }
+            _ => {}
+        }
+        if let Some(line) = describe(msg) {
+            self.push(line);
+        }
+    }
+
+    /// Add a line to the message pane
+    fn push(&mut self, line: String) {
+        // Keep showing the same lines if the pane is scrolled up
+        if self.scroll > 0 {
+            self.scroll += wrap(&line, self.pane_width).len();
+        }
+        self.lines.push(line);
+    }
+
+    fn handle_event(&mut self, event: Event) -> Action {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            // Resizing needs nothing but a redraw
+            return Action::None;
+        };
+        match code {
+            KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Action::Quit,
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Enter if !self.input.trim().is_empty() => {
+                self.scroll = 0;
+                return Action::Send(std::mem::take(&mut self.input));
+            }
+            KeyCode::PageUp => self.scroll += self.pane_height / 2,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.pane_height / 2),
+            KeyCode::End => self.scroll = 0,
+            _ => {}
+        }
+        Action::None
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        // Leave out the sidebar on narrow terminals
+        let sidebar_width = if width >= 3 * SIDEBAR_WIDTH {
+            SIDEBAR_WIDTH
+        } else {
+            0
+        };
+        self.pane_width = width - sidebar_width;
+        self.pane_height = height.saturating_sub(2);
+
+        // Only wrap the lines that can be shown, starting from the bottom
+        let mut rows = Vec::new();
+        for line in self.lines.iter().rev() {
+            if rows.len() >= self.pane_height + self.scroll {
+                break;
+            }
+            rows.extend(wrap(line, self.pane_width).into_iter().rev());
+        }
+        self.scroll = self.scroll.min(rows.len().saturating_sub(self.pane_height));
+        let visible: Vec<_> = rows
+            .iter()
+            .skip(self.scroll)
+            .take(self.pane_height)
+            .rev()
+            .collect();
+        // Messages stick to the bottom of the pane
+        let offset = self.pane_height - visible.len();
+
+        for row in 0..self.pane_height {
+            let text = row.checked_sub(offset).map_or("", |i| visible[i].as_str());
+            queue!(
+                out,
+                cursor::MoveTo(0, row as u16),
+                Print(format!("{text:<0$}", self.pane_width))
+            )?;
+            if sidebar_width > 0 {
+                let entry = match row {
+                    0 => format!("Online ({})", self.users.len()),
+                    row => self.users.get(row - 1).cloned().unwrap_or_default(),
+                };
+                let entry: String = entry.chars().take(sidebar_width - 2).collect();
+                queue!(out, Print(format!("│ {entry}")))?;
+            }
+            queue!(out, terminal::Clear(ClearType::UntilNewLine))?;
+        }
+
+        let connection = match &self.status {
+            Status::Connecting => " connecting ",
+            Status::Connected { .. } => " connected ",
+            Status::Disconnected { .. } => " disconnected ",
+        };
+        let scroll = if self.scroll > 0 {
+            "─ scrolled up, <end> to return "
+        } else {
+            ""
+        };
+        let separator = format!("──{connection}{scroll}");
+        let separator_len = separator.chars().count();
+        queue!(
+            out,
+            cursor::MoveTo(0, self.pane_height as u16),
+            Print(separator),
+            Print("─".repeat(width.saturating_sub(separator_len))),
+        )?;
+
+        // Show the end of the input if it does not fit
+        let input_width = width.saturating_sub(3);
+        let skip = self.input.chars().count().saturating_sub(input_width);
+        let input: String = self.input.chars().skip(skip).collect();
+        queue!(
+            out,
+            cursor::MoveTo(0, self.pane_height as u16 + 1),
+            Print(format!("> {input}")),
+            terminal::Clear(ClearType::UntilNewLine),
+        )?;
+        out.flush()
+    }
+}
+
+/// Split a line into rows of at most `width` characters
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(width.max(1))
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tui::wrap;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("hello world", 5), ["hello", " worl", "d"]);
+        assert_eq!(wrap("héllo", 10), ["héllo"]);
+        assert_eq!(wrap("", 10), [""]);
+    }
+}
@@ -0,0 +1,32 @@
+use std::{io, path::PathBuf};
+
+use anyhow::Result;
+use chat::auth::Credentials;
+use clap::Parser;
+
+/// Add a user to a chat credential store, or change their secret
+#[derive(Parser)]
+struct Args {
+    /// Credential store to update, which is created if it does not exist
+    #[arg(long, env = "CHAT_CREDENTIALS", default_value = "chat-credentials")]
+    credentials: PathBuf,
+    /// Name of the user
+    user: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    anyhow::ensure!(!args.user.contains(':'), "usernames cannot contain ':'");
+    let mut credentials = match Credentials::load(&args.credentials) {
+        Ok(credentials) => credentials,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Credentials::default(),
+        Err(err) => return Err(err.into()),
+    };
+
+    println!("Enter the secret of {} and press <enter>", args.user);
+    let mut secret = String::new();
+    io::stdin().read_line(&mut secret)?;
+    credentials.set(&args.user, secret.trim_end_matches(['\r', '\n']));
+    credentials.save(&args.credentials)?;
+    Ok(())
+}
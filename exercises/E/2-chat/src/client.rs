@@ -0,0 +1,314 @@
+//! The client side of a connection to the server, which is re-established
+//! whenever it is lost.
+//!
+//! Messages the user sends while there is no connection are queued,
+//! and sent as soon as the chat is entered again, back in the same rooms.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    future::Future,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+    select,
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::{rustls::ServerName, TlsConnector};
+
+use crate::{
+    codec::{MessageReader, MessageWriter},
+    format::{self, Format},
+    tls, Message, DEFAULT_ROOM,
+};
+
+/// How long to wait before the first attempt to reconnect
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// The longest time to wait in between two attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Something that happened to the connection
+pub enum Event {
+    /// A message from the server
+    Message(Message),
+    /// The connection changed its state
+    Status(Status),
+}
+
+pub enum Status {
+    Connecting,
+    Connected {
+        format: Format,
+        proposed: Format,
+    },
+    /// The connection was lost, and will be retried after a while
+    Disconnected {
+        error: String,
+        retry_in: Duration,
+    },
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Connecting => write!(f, "Connecting to server..."),
+            Status::Connected { format, proposed } if format != proposed => write!(
+                f,
+                "Connected! Server does not accept {proposed}, using {format} instead"
+            ),
+            Status::Connected { .. } => write!(f, "Connected! You can now enter messages!"),
+            Status::Disconnected { error, retry_in } => write!(
+                f,
+                "Disconnected ({error}), reconnecting in {:.1}s...",
+                retry_in.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// A connection to the server, with or without TLS
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Everything needed to (re)connect to the server
+pub struct Connector {
+    addr: String,
+    /// Set if the connection is secured with TLS
+    tls: Option<(TlsConnector, ServerName)>,
+    format: Format,
+}
+
+impl Connector {
+    /// Connect to the server at `addr` without TLS, proposing `format`
+    pub fn new(addr: impl Into<String>, format: Format) -> Self {
+        Connector {
+            addr: addr.into(),
+            tls: None,
+            format,
+        }
+    }
+
+    /// Secure the connection with TLS, verifying the certificate of the
+    /// server against the CA certificate in the PEM file `ca`
+    pub fn with_tls(mut self, ca: &Path, server_name: &str) -> Result<Self> {
+        self.tls = Some((tls::connector(ca)?, ServerName::try_from(server_name)?));
+        Ok(self)
+    }
+
+    /// Open a connection to the server
+    async fn connect(&self) -> Result<Box<dyn Transport>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        Ok(match &self.tls {
+            Some((connector, server_name)) => {
+                Box::new(connector.connect(server_name.clone(), stream).await?)
+            }
+            None => Box::new(stream),
+        })
+    }
+}
+
+/// Negotiate the wire format over a new connection, proposing `proposed`
+async fn negotiate<S>(
+    mut stream: S,
+    proposed: Format,
+) -> Result<(
+    MessageReader<io::ReadHalf<S>>,
+    MessageWriter<io::WriteHalf<S>>,
+    Format,
+)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let format = format::propose(&mut stream, proposed).await?;
+    let (read, write) = io::split(stream);
+    Ok((
+        MessageReader::new(read, format),
+        MessageWriter::new(write, format),
+        format,
+    ))
+}
+
+/// Stay in the chat, entering it with `handshake` and sending everything
+/// received from `outgoing`, until `outgoing` is closed.
+///
+/// Fails if the chat cannot be entered at all. Once it was entered,
+/// lost connections are re-established with exponential backoff.
+pub async fn run(
+    connector: Connector,
+    handshake: Message,
+    outgoing: mpsc::UnboundedReceiver<Message>,
+    events: mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let connect = || connector.connect();
+    run_over(connect, connector.format, handshake, outgoing, events).await
+}
+
+/// Like [`run`], but over any kind of stream, which `connect` opens
+/// whenever the client (re)connects, proposing `format`
+pub async fn run_over<S, F>(
+    mut connect: impl FnMut() -> F,
+    format: Format,
+    mut handshake: Message,
+    mut outgoing: mpsc::UnboundedReceiver<Message>,
+    events: mpsc::UnboundedSender<Event>,
+) -> Result<()>
+where
+    F: Future<Output = Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut queue = VecDeque::new();
+    let mut rooms = HashSet::from([DEFAULT_ROOM.to_owned()]);
+    let mut entered = false;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let _ = events.send(Event::Status(Status::Connecting));
+        let mut joined = false;
+        let connected = match connect().await {
+            Ok(stream) => negotiate(stream, format).await,
+            Err(err) => Err(err),
+        };
+        let result = match connected {
+            Ok((reader, writer, negotiated)) => {
+                let _ = events.send(Event::Status(Status::Connected {
+                    format: negotiated,
+                    proposed: format,
+                }));
+                let session = Session {
+                    handshake: &mut handshake,
+                    queue: &mut queue,
+                    rooms: &mut rooms,
+                    outgoing: &mut outgoing,
+                    events: &events,
+                };
+                session.run(reader, writer, &mut joined).await
+            }
+            Err(err) => Err(err),
+        };
+        if joined {
+            entered = true;
+            backoff = INITIAL_BACKOFF;
+        }
+        let error = match result {
+            // The user is done
+            Ok(()) => return Ok(()),
+            Err(err) if !entered => return Err(err),
+            Err(err) => err,
+        };
+
+        let _ = events.send(Event::Status(Status::Disconnected {
+            error: error.to_string(),
+            retry_in: backoff,
+        }));
+        let retry = time::sleep(backoff);
+        tokio::pin!(retry);
+        loop {
+            select! {
+                _ = &mut retry => break,
+                msg = outgoing.recv() => match msg {
+                    Some(msg) => queue.push_back(msg),
+                    None => return Ok(()),
+                },
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// The state that outlives a single connection
+struct Session<'a> {
+    handshake: &'a mut Message,
+    /// Messages that still have to be sent
+    queue: &'a mut VecDeque<Message>,
+    /// The rooms the user is in, to join them again after reconnecting
+    rooms: &'a mut HashSet<String>,
+    outgoing: &'a mut mpsc::UnboundedReceiver<Message>,
+    events: &'a mpsc::UnboundedSender<Event>,
+}
+
+impl Session<'_> {
+    /// Enter the chat over a new connection, and stay until either the
+    /// connection is lost or `outgoing` is closed. Sets `joined` once the
+    /// server let the user in, which it confirms by announcing them.
+    async fn run(
+        self,
+        reader: MessageReader<impl AsyncRead + Unpin>,
+        mut writer: MessageWriter<impl AsyncWrite + Unpin>,
+        joined: &mut bool,
+    ) -> Result<()> {
+        writer.write_message(self.handshake).await?;
+        // The server starts every connection in the default room only
+        for room in self.rooms.iter().filter(|room| *room != DEFAULT_ROOM) {
+            let join = Message::Join { room: room.clone() };
+            writer.write_message(&join).await?;
+        }
+        if !self.rooms.contains(DEFAULT_ROOM) {
+            let leave = Message::Leave {
+                room: DEFAULT_ROOM.to_owned(),
+            };
+            writer.write_message(&leave).await?;
+        }
+        // Messages are only dropped from the queue once they were written
+        while let Some(msg) = self.queue.front() {
+            writer.write_message(msg).await?;
+            self.queue.pop_front();
+        }
+
+        let mut messages = Box::pin(reader.into_stream());
+        loop {
+            select! {
+                msg = messages.next() => {
+                    let Some(msg) = msg.transpose()? else {
+                        return Err(anyhow::format_err!("connection closed by the server"));
+                    };
+                    if matches!(&msg, Message::User(user) if Some(user.as_str()) == name(self.handshake)) {
+                        *joined = true;
+                    }
+                    match &msg {
+                        Message::Rename { from, to } => rename(self.handshake, from, to),
+                        Message::Join { room } => {
+                            self.rooms.insert(room.clone());
+                        }
+                        Message::Leave { room } => {
+                            self.rooms.remove(room);
+                        }
+                        _ => {}
+                    }
+                    let _ = self.events.send(Event::Message(msg));
+                }
+                msg = self.outgoing.recv() => {
+                    let Some(msg) = msg else {
+                        return Ok(());
+                    };
+                    self.queue.push_back(msg);
+                    writer.write_message(&self.queue[0]).await?;
+                    self.queue.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// The name the user enters the chat with
+fn name(handshake: &Message) -> Option<&str> {
+    match handshake {
+        Message::User(user) | Message::Auth { user, .. } => Some(user),
+        _ => None,
+    }
+}
+
+/// Make sure the user enters the chat under their new name after a rename
+fn rename(handshake: &mut Message, from: &str, to: &str) {
+    if let Message::User(user) | Message::Auth { user, .. } = handshake {
+        if user == from {
+            *user = to.to_owned();
+        }
+    }
+}
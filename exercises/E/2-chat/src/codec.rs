@@ -0,0 +1,271 @@
+//! Length-prefixed framing of [`Message`]s.
+//!
+//! Every frame consists of the length of the payload as a big-endian `u32`,
+//! followed by the payload: the message serialized in the [`Format`] of the
+//! connection.
+
+use std::fmt::Display;
+
+use futures::{sink, stream, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    format::{self, Format},
+    Message,
+};
+
+/// The default maximum payload length of a single frame
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the underlying stream failed
+    Io(std::io::Error),
+    /// The stream was closed in the middle of a frame
+    UnexpectedEof,
+    /// A frame is longer than the configured maximum length
+    FrameTooLarge(usize),
+    /// The payload of a frame could not be (de)serialized
+    Serde(format::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::UnexpectedEof => write!(f, "connection closed in the middle of a frame"),
+            Error::FrameTooLarge(len) => write!(f, "frame of {len} bytes is too large"),
+            Error::Serde(err) => write!(f, "invalid message: {err}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<format::Error> for Error {
+    fn from(err: format::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+/// Reads length-prefixed [`Message`]s from an [`AsyncRead`]
+pub struct MessageReader<R> {
+    reader: R,
+    format: Format,
+    max_frame_len: usize,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R, format: Format) -> Self {
+        Self::with_max_frame_len(reader, format, MAX_FRAME_LEN)
+    }
+
+    /// Create a reader that rejects frames longer than `max_frame_len`
+    pub fn with_max_frame_len(reader: R, format: Format, max_frame_len: usize) -> Self {
+        MessageReader {
+            reader,
+            format,
+            max_frame_len,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Read the next message. Returns `Ok(None)` if the stream was closed
+    /// in between two frames.
+    ///
+    /// This method is not cancel safe: when the returned future is dropped
+    /// before completing, part of a frame may have been consumed.
+    pub async fn read_message(&mut self) -> Result<Option<Message>, Error> {
+        let mut len = [0; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.reader.read(&mut len[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(Error::UnexpectedEof),
+                n => filled += n,
+            }
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_frame_len {
+            return Err(Error::FrameTooLarge(len));
+        }
+        self.buffer.resize(len, 0);
+        self.reader.read_exact(&mut self.buffer).await?;
+        Ok(Some(self.format.deserialize(&self.buffer)?))
+    }
+
+    /// Turn the reader into a [`Stream`] of messages, which ends when the
+    /// underlying stream is closed in between two frames
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, Error>> {
+        stream::unfold(self, |mut reader| async move {
+            let msg = reader.read_message().await.transpose()?;
+            Some((msg, reader))
+        })
+    }
+}
+
+/// Writes length-prefixed [`Message`]s to an [`AsyncWrite`]
+pub struct MessageWriter<W> {
+    writer: W,
+    format: Format,
+    max_frame_len: usize,
+    buffer: Vec<u8>,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub fn new(writer: W, format: Format) -> Self {
+        Self::with_max_frame_len(writer, format, MAX_FRAME_LEN)
+    }
+
+    /// Create a writer that refuses to write frames longer than `max_frame_len`
+    pub fn with_max_frame_len(writer: W, format: Format, max_frame_len: usize) -> Self {
+        MessageWriter {
+            writer,
+            format,
+            max_frame_len,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Write a message as a single frame and flush it
+    pub async fn write_message(&mut self, msg: &Message) -> Result<(), Error> {
+        // Reserve space for the length prefix, which is filled in afterwards
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&[0; 4]);
+        self.format.serialize(msg, &mut self.buffer)?;
+
+        let len = self.buffer.len() - 4;
+        if len > self.max_frame_len {
+            return Err(Error::FrameTooLarge(len));
+        }
+        self.buffer[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        self.writer.write_all(&self.buffer).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Turn the writer into a [`Sink`] of messages
+    pub fn into_sink(self) -> impl Sink<Message, Error = Error> {
+        sink::unfold(self, |mut writer, msg: Message| async move {
+            writer.write_message(&msg).await?;
+            Ok(writer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncWriteExt};
+
+    use crate::{
+        codec::{Error, MessageReader, MessageWriter},
+        format::Format,
+        Message,
+    };
+
+    #[tokio::test]
+    async fn test_write_read() {
+        for format in Format::ALL {
+            let (client, server) = io::duplex(1024);
+            let mut writer = MessageWriter::new(client, format);
+            let mut reader = MessageReader::new(server, format);
+
+            for i in 0..3 {
+                writer
+                    .write_message(&Message::ClientMessage(i.to_string()))
+                    .await
+                    .unwrap();
+            }
+            drop(writer);
+            for i in 0..3 {
+                assert!(matches!(
+                    reader.read_message().await.unwrap(),
+                    Some(Message::ClientMessage(content)) if content == i.to_string()
+                ));
+            }
+            assert!(reader.read_message().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_reads() {
+        // A one byte buffer makes every read return a single byte
+        let (client, server) = io::duplex(1);
+        let content = "a message that does not fit in a single read".to_owned();
+
+        let write_task = tokio::spawn({
+            let content = content.clone();
+            async move {
+                let mut writer = MessageWriter::new(client, Format::Json);
+                writer
+                    .write_message(&Message::ClientMessage(content))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut reader = MessageReader::new(server, Format::Json);
+        assert!(matches!(
+            reader.read_message().await.unwrap(),
+            Some(Message::ClientMessage(c)) if c == content
+        ));
+        write_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_eof() {
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = MessageReader::new(server, Format::Json);
+
+        // Only half of the length prefix
+        client.write_all(&[0, 0]).await.unwrap();
+        drop(client);
+        assert!(matches!(
+            reader.read_message().await,
+            Err(Error::UnexpectedEof)
+        ));
+
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = MessageReader::new(server, Format::Json);
+
+        // A length prefix without the payload it announces
+        client.write_all(&10u32.to_be_bytes()).await.unwrap();
+        drop(client);
+        assert!(matches!(
+            reader.read_message().await,
+            Err(Error::UnexpectedEof)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames() {
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = MessageReader::with_max_frame_len(server, Format::Json, 16);
+
+        // The reader must not wait for the payload of an oversized frame
+        client.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        assert!(matches!(
+            reader.read_message().await,
+            Err(Error::FrameTooLarge(1000))
+        ));
+
+        let (client, _server) = io::duplex(1024);
+        let mut writer = MessageWriter::with_max_frame_len(client, Format::Json, 16);
+        let msg = Message::ClientMessage("too long to fit in 16 bytes".to_owned());
+        assert!(matches!(
+            writer.write_message(&msg).await,
+            Err(Error::FrameTooLarge(_))
+        ));
+    }
+}
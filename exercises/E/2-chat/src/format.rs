@@ -0,0 +1,169 @@
+//! Wire formats in which [`Message`]s can be encoded, and the negotiation
+//! of the format of a connection.
+//!
+//! Before anything else, the client sends a single byte identifying the
+//! format it prefers. The server answers with a single byte identifying the
+//! format it chose, which is the client's preference if the server accepts it,
+//! and the server's own preference otherwise.
+
+use std::{fmt::Display, str::FromStr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Message;
+
+/// An error (de)serializing a message
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+    Bincode,
+}
+
+impl Format {
+    /// All supported formats
+    pub const ALL: [Format; 3] = [Format::Json, Format::MessagePack, Format::Bincode];
+
+    /// Append the encoding of `msg` to `buf`
+    pub fn serialize(self, msg: &Message, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Format::Json => serde_json::to_writer(buf, msg)?,
+            Format::MessagePack => rmp_serde::encode::write(buf, msg)?,
+            Format::Bincode => bincode::serialize_into(buf, msg)?,
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(self, bytes: &[u8]) -> Result<Message, Error> {
+        let msg = match self {
+            Format::Json => serde_json::from_slice(bytes)?,
+            Format::MessagePack => rmp_serde::from_slice(bytes)?,
+            Format::Bincode => bincode::deserialize(bytes)?,
+        };
+        Ok(msg)
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Format::Json => 1,
+            Format::MessagePack => 2,
+            Format::Bincode => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Format::ALL.into_iter().find(|format| format.id() == id)
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::MessagePack => write!(f, "msgpack"),
+            Format::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.to_string() == s)
+            .ok_or_else(|| format!("unknown format {s}, expected json, msgpack or bincode"))
+    }
+}
+
+/// Propose `format` to the server, returning the format the server chose
+pub async fn propose<S>(stream: &mut S, format: Format) -> std::io::Result<Format>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&[format.id()]).await?;
+    stream.flush().await?;
+    let id = stream.read_u8().await?;
+    Format::from_id(id).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("server chose unknown format {id}"),
+        )
+    })
+}
+
+/// Choose the format of a connection to a client, given the formats the
+/// server accepts in order of preference
+pub async fn choose<S>(stream: &mut S, accepted: &[Format]) -> std::io::Result<Format>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let proposed = Format::from_id(stream.read_u8().await?);
+    let format = match proposed {
+        Some(format) if accepted.contains(&format) => format,
+        _ => accepted.first().copied().unwrap_or_default(),
+    };
+    stream.write_all(&[format.id()]).await?;
+    stream.flush().await?;
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use crate::{
+        format::{choose, propose, Format},
+        Message,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let msg = Message::Chat {
+            room: "lobby".to_owned(),
+            user: "alice".to_owned(),
+            content: "hello".to_owned(),
+        };
+        for format in Format::ALL {
+            let mut buf = Vec::new();
+            format.serialize(&msg, &mut buf).unwrap();
+            assert!(matches!(
+                format.deserialize(&buf).unwrap(),
+                Message::Chat { room, user, content }
+                    if room == "lobby" && user == "alice" && content == "hello"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        for format in Format::ALL {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let (mut client, mut server) = io::duplex(16);
+        let (proposed, chosen) = tokio::join!(
+            propose(&mut client, Format::Bincode),
+            choose(&mut server, &Format::ALL)
+        );
+        assert_eq!(proposed.unwrap(), Format::Bincode);
+        assert_eq!(chosen.unwrap(), Format::Bincode);
+
+        // The server falls back to its own preference
+        let (mut client, mut server) = io::duplex(16);
+        let (proposed, chosen) = tokio::join!(
+            propose(&mut client, Format::Bincode),
+            choose(&mut server, &[Format::MessagePack, Format::Json])
+        );
+        assert_eq!(proposed.unwrap(), Format::MessagePack);
+        assert_eq!(chosen.unwrap(), Format::MessagePack);
+    }
+}
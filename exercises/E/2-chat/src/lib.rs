@@ -1,13 +1,60 @@
+pub mod auth;
+pub mod client;
+pub mod codec;
+pub mod format;
+pub mod limit;
+pub mod plugin;
+pub mod server;
+pub mod tls;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// The room every user is put in when they enter the chat
+pub const DEFAULT_ROOM: &str = "lobby";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Message {
-    /// A user enters the chat and provides their username
+    /// A user enters the chat and provides their username.
+    /// Sent again later on, it requests to change the username.
     User(String),
+    /// Sent by the server when a user left the chat
+    Left(String),
+    /// A client asks which users are online
+    ListUsers,
+    /// Sent by the server in response to `ListUsers`,
+    /// containing the usernames of everyone online
+    Users(Vec<String>),
+    /// Sent by the server when a user changed their username
+    Rename { from: String, to: String },
     /// A message sent from a client,
     /// that needs to be matched with their username
     ClientMessage(String),
     /// A message sent from the server to the clients,
+    /// containing the room and username of the sender and the message content
+    Chat {
+        room: String,
+        user: String,
+        content: String,
+    },
+    /// A private message sent from a client to a single other user
+    Direct { to: String, content: String },
+    /// A private message sent from the server to its recipient,
     /// containing the username of the sender and the message content
-    Chat { user: String, content: String },
+    DirectChat { user: String, content: String },
+    /// Sent by the server when it could not handle a client's request
+    Error(String),
+    /// Sent by the server when a client could not keep up,
+    /// containing the number of messages it missed
+    Skipped(u64),
+    /// Sent by the server right before it closes all connections
+    ServerShutdown,
+    /// A client asks to join a room, which is confirmed by the server
+    /// echoing the message back
+    Join { room: String },
+    /// A client asks to leave a room, which is confirmed by the server
+    /// echoing the message back
+    Leave { room: String },
+    /// A user enters the chat with the secret proving they own the username.
+    /// Replaces `User` as initial message if the server requires authentication.
+    Auth { user: String, secret: String },
 }
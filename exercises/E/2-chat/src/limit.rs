@@ -0,0 +1,67 @@
+//! Limiting the rate at which clients send messages
+
+use std::time::Instant;
+
+/// A token bucket, which lets through `burst` messages at once,
+/// and refills at `rate` messages per second
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(rate: f64, burst: u32) -> Self {
+        TokenBucket {
+            rate,
+            burst: burst.into(),
+            tokens: burst.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token for a message arriving at `now`.
+    /// Returns `false` if the message exceeds the rate limit.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::limit::TokenBucket;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(bucket.try_take(now));
+        }
+        assert!(!bucket.try_take(now));
+
+        // Half a second refills a single token
+        let now = now + Duration::from_millis(500);
+        assert!(bucket.try_take(now));
+        assert!(!bucket.try_take(now));
+
+        // The bucket never holds more than the burst
+        let now = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(now));
+        }
+        assert!(!bucket.try_take(now));
+    }
+}
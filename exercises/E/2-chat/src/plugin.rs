@@ -0,0 +1,234 @@
+//! Plugins extending the chat on the server side, e.g. with commands like
+//! `/roll`. Clients send commands they do not know themselves as regular
+//! messages, which plugins can pick up.
+
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::Message;
+
+/// A plugin sees every message a client sends before the server handles it
+pub trait ChatPlugin: Send + Sync {
+    /// The name under which the plugin is configured, and replies to users
+    fn name(&self) -> &str;
+
+    /// React to a message sent by `user`
+    fn on_message(&self, user: &str, msg: &Message) -> Outcome;
+}
+
+/// What a plugin wants to happen after seeing a message
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Outcome {
+    pub replies: Vec<Reply>,
+    /// Whether the server, and any plugins after this one,
+    /// should ignore the message
+    pub suppress: bool,
+}
+
+impl Outcome {
+    /// Let the message through without replying
+    pub fn pass() -> Self {
+        Outcome::default()
+    }
+
+    /// Reply to a command, which is not handled any further
+    pub fn handled(reply: Reply) -> Self {
+        Outcome {
+            replies: vec![reply],
+            suppress: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// Sent to the user whose message the plugin saw only
+    Private(String),
+    /// Posted to all rooms the user is in
+    Public(String),
+}
+
+/// The built-in plugin called `name`, if there is one
+pub fn builtin(name: &str) -> Option<Box<dyn ChatPlugin>> {
+    match name {
+        "roll" => Some(Box::new(Roll::new())),
+        "time" => Some(Box::new(Time)),
+        _ => None,
+    }
+}
+
+/// The arguments of `msg` if it is the command `/name`
+pub fn command_args<'a>(msg: &'a Message, name: &str) -> Option<&'a str> {
+    let Message::ClientMessage(content) = msg else {
+        return None;
+    };
+    let args = content.trim().strip_prefix('/')?.strip_prefix(name)?;
+    if !args.is_empty() && !args.starts_with(char::is_whitespace) {
+        // Another command starting with the same letters
+        return None;
+    }
+    Some(args.trim())
+}
+
+/// Rolls dice for everyone to see: `/roll` rolls a six-sided die,
+/// `/roll 2d20` two twenty-sided ones
+pub struct Roll {
+    rng: Mutex<StdRng>,
+}
+
+impl Roll {
+    pub fn new() -> Self {
+        Roll {
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    /// Roll dice in a predictable way
+    pub fn with_seed(seed: u64) -> Self {
+        Roll {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Default for Roll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatPlugin for Roll {
+    fn name(&self) -> &str {
+        "roll"
+    }
+
+    fn on_message(&self, user: &str, msg: &Message) -> Outcome {
+        let Some(args) = command_args(msg, "roll") else {
+            return Outcome::pass();
+        };
+        let dice = if args.is_empty() { "1d6" } else { args };
+        let parsed = dice
+            .split_once('d')
+            .and_then(|(n, sides)| Some((n.parse::<u32>().ok()?, sides.parse::<u32>().ok()?)));
+        let Some((n @ 1..=100, sides @ 2..=1000)) = parsed else {
+            return Outcome::handled(Reply::Private(
+                "usage: /roll [<n>d<sides>], e.g. /roll 2d6".to_owned(),
+            ));
+        };
+
+        let mut rng = self.rng.lock().unwrap();
+        let rolls: Vec<u32> = (0..n).map(|_| rng.gen_range(1..=sides)).collect();
+        let total: u32 = rolls.iter().sum();
+        let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        let content = if n == 1 {
+            format!("{user} rolled {dice}: {total}")
+        } else {
+            format!("{user} rolled {dice}: {} = {total}", rolls.join(" + "))
+        };
+        Outcome::handled(Reply::Public(content))
+    }
+}
+
+/// Tells the user the time on the server: `/time`
+pub struct Time;
+
+impl ChatPlugin for Time {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn on_message(&self, _user: &str, msg: &Message) -> Outcome {
+        if command_args(msg, "time").is_none() {
+            return Outcome::pass();
+        }
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let (hours, minutes, seconds) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+        Outcome::handled(Reply::Private(format!(
+            "The time on the server is {hours:02}:{minutes:02}:{seconds:02} UTC"
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        plugin::{builtin, command_args, ChatPlugin, Outcome, Reply, Roll, Time},
+        Message,
+    };
+
+    fn client_message(content: &str) -> Message {
+        Message::ClientMessage(content.to_owned())
+    }
+
+    #[test]
+    fn test_command_args() {
+        assert_eq!(command_args(&client_message("/roll"), "roll"), Some(""));
+        assert_eq!(
+            command_args(&client_message(" /roll 2d6 "), "roll"),
+            Some("2d6")
+        );
+        assert_eq!(command_args(&client_message("/rolls"), "roll"), None);
+        assert_eq!(command_args(&client_message("roll"), "roll"), None);
+        assert_eq!(command_args(&Message::ListUsers, "roll"), None);
+    }
+
+    #[test]
+    fn test_roll() {
+        let roll = Roll::with_seed(42);
+        assert_eq!(
+            roll.on_message("alice", &client_message("hi")),
+            Outcome::pass()
+        );
+
+        let outcome = roll.on_message("alice", &client_message("/roll 3d6"));
+        assert!(outcome.suppress);
+        let [Reply::Public(content)] = &outcome.replies[..] else {
+            panic!("unexpected {outcome:?}");
+        };
+        let (rolls, total) = content
+            .strip_prefix("alice rolled 3d6: ")
+            .and_then(|rolls| rolls.split_once(" = "))
+            .unwrap();
+        let rolls: Vec<u32> = rolls.split(" + ").map(|r| r.parse().unwrap()).collect();
+        assert_eq!(rolls.len(), 3);
+        assert!(rolls.iter().all(|r| (1..=6).contains(r)));
+        assert_eq!(rolls.iter().sum::<u32>(), total.parse::<u32>().unwrap());
+
+        // The same seed rolls the same dice
+        assert_eq!(
+            Roll::with_seed(42).on_message("alice", &client_message("/roll 3d6")),
+            outcome
+        );
+
+        let outcome = roll.on_message("alice", &client_message("/roll many"));
+        assert!(outcome.suppress);
+        assert!(
+            matches!(&outcome.replies[..], [Reply::Private(usage)] if usage.starts_with("usage"))
+        );
+    }
+
+    #[test]
+    fn test_time() {
+        let outcome = Time.on_message("alice", &client_message("/time"));
+        assert!(outcome.suppress);
+        let [Reply::Private(content)] = &outcome.replies[..] else {
+            panic!("unexpected {outcome:?}");
+        };
+        let time = content.strip_prefix("The time on the server is ").unwrap();
+        assert_eq!(time.len(), "00:00:00 UTC".len());
+    }
+
+    #[test]
+    fn test_builtin() {
+        for name in ["roll", "time"] {
+            assert_eq!(builtin(name).unwrap().name(), name);
+        }
+        assert!(builtin("weather").is_none());
+    }
+}
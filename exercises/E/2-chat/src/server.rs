@@ -0,0 +1,823 @@
+//! The chat server, which serves clients over any kind of stream,
+//! optionally secured with TLS, as well as browsers over websockets
+
+mod admin;
+mod state;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{
+        ws::{self, WebSocket},
+        ConnectInfo, WebSocketUpgrade,
+    },
+    response::Response,
+    routing::get,
+    Router,
+};
+use clap::Parser;
+use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    join,
+    net::TcpListener,
+    select,
+    sync::{broadcast, mpsc, watch, Semaphore},
+    task::{self, JoinSet},
+    time,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamMap,
+};
+
+use crate::{
+    auth::Credentials,
+    codec::{MessageReader, MessageWriter},
+    format::{self, Format},
+    limit::TokenBucket,
+    plugin::{self, Reply},
+    tls, Message, DEFAULT_ROOM,
+};
+use admin::{count, Metrics, Peers, Transport};
+use state::{Bans, History, Rooms, Sessions, State, Users};
+
+/// How long to wait for connections to close when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Chat server
+#[derive(Parser)]
+pub struct Config {
+    /// Address to listen on for connections
+    #[arg(long, env = "CHAT_ADDR", default_value = "127.0.0.1:8000")]
+    pub addr: String,
+    /// Number of messages buffered for each client before it starts lagging
+    #[arg(long, env = "CHAT_CAPACITY", default_value_t = 1024)]
+    capacity: usize,
+    /// File to which all chat messages are appended
+    #[arg(long, default_value = "chat-history.jsonl")]
+    history: PathBuf,
+    /// Number of recent messages replayed to a user joining a room
+    #[arg(long, default_value_t = 10)]
+    replay: usize,
+    /// Wire formats accepted from clients, in order of preference
+    #[arg(long, value_delimiter = ',', default_value = "json,msgpack,bincode")]
+    format: Vec<Format>,
+    /// PEM file with the TLS certificate chain, enables TLS together with `--tls-key`
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Credential store users have to authenticate against.
+    /// Without it, anyone can enter the chat with any free username.
+    #[arg(long, env = "CHAT_CREDENTIALS")]
+    credentials: Option<PathBuf>,
+    /// Address to listen on for browsers connecting through a websocket at `/ws`
+    #[arg(long, env = "CHAT_WS_ADDR")]
+    ws_addr: Option<String>,
+    /// Local address to serve metrics at `/metrics`, and the connected peers
+    /// at `/peers` on
+    #[arg(long, env = "CHAT_ADMIN_ADDR")]
+    admin_addr: Option<String>,
+    #[command(flatten)]
+    limits: Limits,
+    /// Built-in plugins that see every message, in the order they see them
+    #[arg(long, value_delimiter = ',', default_value = "roll,time")]
+    plugins: Vec<String>,
+}
+
+/// Limits protecting the server from clients flooding it
+#[derive(clap::Args)]
+struct Limits {
+    /// Messages per second a client may send on average
+    #[arg(long, env = "CHAT_RATE", default_value_t = 5.0)]
+    rate: f64,
+    /// Messages a client may send at once before it is rate limited
+    #[arg(long, env = "CHAT_BURST", default_value_t = 10)]
+    burst: u32,
+    /// Maximum length of the content of a message, in bytes
+    #[arg(long, env = "CHAT_MAX_CONTENT_LEN", default_value_t = 4096)]
+    max_content_len: usize,
+    /// Maximum length of usernames and room names, in bytes
+    #[arg(long, env = "CHAT_MAX_NAME_LEN", default_value_t = 32)]
+    max_name_len: usize,
+    /// Number of violations of the limits after which a client is kicked
+    #[arg(long, env = "CHAT_MAX_VIOLATIONS", default_value_t = 5)]
+    max_violations: u32,
+    /// Number of seconds a kicked client may not reconnect
+    #[arg(long, env = "CHAT_BAN_SECS", default_value_t = 300)]
+    ban_secs: u64,
+    /// Number of secrets verified at once. Verifying a secret takes a lot
+    /// of time and memory on purpose.
+    #[arg(long, env = "CHAT_MAX_AUTH_JOBS", default_value_t = 4)]
+    max_auth_jobs: usize,
+}
+
+impl Limits {
+    /// Check a message a client sent against the limits,
+    /// returning what is wrong with it if it violates them
+    fn check(&self, bucket: &mut TokenBucket, msg: &Message) -> Option<String> {
+        if !bucket.try_take(Instant::now()) {
+            return Some("too many messages, slow down".to_owned());
+        }
+        match msg {
+            Message::ClientMessage(content) | Message::Direct { content, .. } => (content.len()
+                > self.max_content_len)
+                .then(|| format!("message longer than {} bytes", self.max_content_len)),
+            Message::User(name) | Message::Join { room: name } | Message::Leave { room: name } => {
+                self.check_name(name)
+            }
+            _ => None,
+        }
+    }
+
+    /// Check a username or room name against the limits,
+    /// returning what is wrong with it if it violates them
+    fn check_name(&self, name: &str) -> Option<String> {
+        (name.len() > self.max_name_len)
+            .then(|| format!("name longer than {} bytes", self.max_name_len))
+    }
+}
+
+/// Instructions from the incoming half of a connection to its outgoing half
+#[derive(Debug)]
+enum Command {
+    /// Start forwarding the announcements to every client, once the client
+    /// entered the chat
+    Enter(broadcast::Receiver<Message>),
+    /// Send the recent messages of a room to the client,
+    /// and start forwarding its new messages
+    Subscribe {
+        room: String,
+        backlog: Vec<Message>,
+        rx: broadcast::Receiver<Message>,
+    },
+    /// Stop forwarding the messages of a room to the client
+    Unsubscribe(String),
+    /// Send a message to this client only
+    Send(Message),
+}
+
+/// A chat server that is set up, and ready to serve clients
+pub struct Server {
+    state: Arc<State>,
+    /// The HTTP servers to run alongside, with the listeners they serve
+    http: Vec<(&'static str, std::net::TcpListener, Router)>,
+    ws_addr: Option<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+}
+
+impl Server {
+    /// Set up a server as configured, loading the chat history and binding
+    /// the listeners of the websocket gateway and admin endpoint, if enabled
+    pub fn new(config: Config) -> Result<Self> {
+        let history = History::open(&config.history, config.replay)?;
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+            _ => None,
+        };
+        let credentials = config
+            .credentials
+            .as_deref()
+            .map(Credentials::load)
+            .transpose()?;
+        let plugins = config
+            .plugins
+            .iter()
+            .map(|name| {
+                plugin::builtin(name).ok_or_else(|| anyhow::format_err!("unknown plugin {name}"))
+            })
+            .collect::<Result<_>>()?;
+        let (tx, _) = broadcast::channel(config.capacity);
+        let state = Arc::new(State {
+            tx,
+            rooms: Rooms::new(config.capacity),
+            users: Users::default(),
+            history: Mutex::new(history),
+            formats: config.format,
+            tls,
+            credentials,
+            auth_jobs: Semaphore::new(config.limits.max_auth_jobs),
+            limits: config.limits,
+            bans: Bans::default(),
+            plugins,
+            metrics: Metrics::default(),
+            peers: Peers::default(),
+            websockets: Sessions::default(),
+        });
+
+        let mut server = Server {
+            state,
+            http: Vec::new(),
+            ws_addr: None,
+            admin_addr: None,
+        };
+        if let Some(ws_addr) = &config.ws_addr {
+            let listener = std::net::TcpListener::bind(ws_addr)?;
+            server.ws_addr = Some(listener.local_addr()?);
+            let app = Router::new()
+                .route("/ws", get(upgrade_websocket))
+                .with_state(server.state.clone());
+            server.http.push(("websocket gateway", listener, app));
+        }
+        if let Some(admin_addr) = &config.admin_addr {
+            let listener = std::net::TcpListener::bind(admin_addr)?;
+            server.admin_addr = Some(listener.local_addr()?);
+            let app = admin::router(server.state.clone());
+            server.http.push(("admin endpoint", listener, app));
+        }
+        Ok(server)
+    }
+
+    /// The address of the websocket gateway, if enabled
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws_addr
+    }
+
+    /// The address of the admin endpoint, if enabled
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// Serve clients connecting to `listener` until `shutdown` completes
+    pub async fn run(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        let incoming = futures::stream::poll_fn(move |cx| listener.poll_accept(cx).map(Some));
+        self.serve(incoming, shutdown).await
+    }
+
+    /// Serve the clients of the connections `incoming` yields, along with
+    /// the addresses of their peers, until `shutdown` completes or there
+    /// are no more connections. The clients are then told the server shuts
+    /// down, and given a few seconds to disconnect.
+    pub async fn serve<S>(
+        self,
+        incoming: impl Stream<Item = io::Result<(S, SocketAddr)>>,
+        shutdown: impl Future<Output = Result<()>>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let Server { state, http, .. } = self;
+        let mut incoming = Box::pin(incoming);
+        tokio::pin!(shutdown);
+        let mut connections = JoinSet::new();
+
+        // The HTTP servers stop when this is dropped
+        let (stop_http, http_stopped) = watch::channel(());
+        for (name, listener, app) in http {
+            connections.spawn(serve_http(name, listener, app, http_stopped.clone())?);
+        }
+
+        loop {
+            let (stream, peer_addr) = select! {
+                res = &mut shutdown => {
+                    res?;
+                    break;
+                }
+                accepted = incoming.next() => match accepted {
+                    Some(accepted) => accepted?,
+                    None => break,
+                },
+                // Clean up connections that are done
+                Some(_) = connections.join_next() => continue,
+            };
+            println!("[peer@{peer_addr}] connection established");
+            connections.spawn(handle_connection(stream, peer_addr, state.clone()));
+        }
+
+        println!("shutting down");
+        drop(incoming);
+        drop(stop_http);
+        // Clients are disconnected after they received this message
+        let _ = state.tx.send(Message::ServerShutdown);
+        let closed = async {
+            while connections.join_next().await.is_some() {}
+            state.websockets.all_closed().await;
+        };
+        if time::timeout(SHUTDOWN_TIMEOUT, closed).await.is_err() {
+            eprintln!("not all connections closed in time, aborting them");
+            connections.shutdown().await;
+        }
+        Ok(())
+    }
+}
+
+/// Serve `app` over HTTP until `stopped` is closed
+fn serve_http(
+    name: &'static str,
+    listener: std::net::TcpListener,
+    app: Router,
+    mut stopped: watch::Receiver<()>,
+) -> Result<impl std::future::Future<Output = ()>> {
+    let server = axum::Server::from_tcp(listener)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            let _ = stopped.changed().await;
+        });
+    Ok(async move {
+        if let Err(err) = server.await {
+            eprintln!("{name} ERROR: {err}");
+        }
+    })
+}
+
+/// Set up TLS if enabled, and handle the connection
+async fn handle_connection<S>(stream: S, peer_addr: SocketAddr, state: Arc<State>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(acceptor) = state.tls.clone() else {
+        return handle_stream(stream, peer_addr, state).await;
+    };
+    match acceptor.accept(stream).await {
+        Ok(stream) => handle_stream(stream, peer_addr, state).await,
+        Err(err) => {
+            eprintln!("[peer@{peer_addr}] ERROR: TLS handshake failed: {err}");
+            count(&state.metrics.errors, 1);
+        }
+    }
+}
+
+/// Negotiate the wire format with a client, and handle both halves of the connection
+async fn handle_stream<S>(mut stream: S, peer_addr: SocketAddr, state: Arc<State>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let format = match format::choose(&mut stream, &state.formats).await {
+        Ok(format) => format,
+        Err(err) => {
+            eprintln!("[peer@{peer_addr}] ERROR: {err}");
+            count(&state.metrics.errors, 1);
+            return;
+        }
+    };
+    println!("[peer@{peer_addr}] using {format} format");
+    let transport = match state.tls {
+        Some(_) => Transport::Tls,
+        None => Transport::Tcp,
+    };
+    let (read, write) = io::split(stream);
+    let messages = MessageReader::new(read, format)
+        .into_stream()
+        .map_err(anyhow::Error::from);
+    let sink = MessageWriter::new(write, format)
+        .into_sink()
+        .sink_map_err(anyhow::Error::from);
+    handle_client(
+        Box::pin(messages),
+        Box::pin(sink),
+        peer_addr,
+        transport,
+        state,
+    )
+    .await;
+}
+
+async fn upgrade_websocket(
+    ws: WebSocketUpgrade,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+    axum::extract::State(state): axum::extract::State<Arc<State>>,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_websocket(socket, peer_addr, state))
+}
+
+/// Handle a client connected through the websocket gateway,
+/// which sends and receives messages as JSON in text frames
+async fn handle_websocket(socket: WebSocket, peer_addr: SocketAddr, state: Arc<State>) {
+    // Not among the connections `serve` waits for when shutting down
+    let sessions = state.clone();
+    let _session = sessions.websockets.open();
+    println!("[peer@{peer_addr}] websocket connection established");
+    let (sink, stream) = socket.split();
+    let messages = stream.filter_map(|frame| async move {
+        match frame {
+            Ok(ws::Message::Text(text)) => Some(serde_json::from_str(&text).map_err(Into::into)),
+            // Other frames do not carry chat messages
+            Ok(_) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    });
+    let sink = sink.with(|msg: Message| async move {
+        Ok::<_, anyhow::Error>(ws::Message::Text(serde_json::to_string(&msg)?))
+    });
+    let transport = Transport::Websocket;
+    handle_client(
+        Box::pin(messages),
+        Box::pin(sink),
+        peer_addr,
+        transport,
+        state,
+    )
+    .await;
+}
+
+/// Handle both halves of the connection to a client,
+/// regardless of how messages are transported
+async fn handle_client(
+    messages: impl Stream<Item = Result<Message>> + Unpin,
+    sink: impl Sink<Message, Error = anyhow::Error> + Unpin,
+    peer_addr: SocketAddr,
+    transport: Transport,
+    state: Arc<State>,
+) {
+    count(&state.metrics.connections, 1);
+    state.peers.insert(peer_addr, transport);
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+    // Subscribe right away, so even clients that did not enter the chat
+    // yet learn about the shutdown. Nothing else is forwarded from it.
+    let shutdown = state.tx.subscribe();
+
+    let incoming = async {
+        match handle_incoming(messages, peer_addr, state.clone(), commands_tx).await {
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("[peer@{peer_addr}] ERROR: {err}");
+                count(&state.metrics.errors, 1);
+            }
+        }
+    };
+
+    let outgoing = async {
+        match handle_outgoing(sink, shutdown, commands_rx, &state.metrics).await {
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("[peer@{peer_addr}] ERROR: {err}");
+                count(&state.metrics.errors, 1);
+            }
+        }
+    };
+
+    join!(incoming, outgoing);
+    state.peers.remove(peer_addr);
+}
+
+async fn handle_incoming(
+    mut messages: impl Stream<Item = Result<Message>> + Unpin,
+    peer_addr: SocketAddr,
+    state: Arc<State>,
+    commands: mpsc::UnboundedSender<Command>,
+) -> Result<()> {
+    let initial_message = select! {
+        msg = messages.next() => msg.transpose()?,
+        // The outgoing half of the connection is gone
+        _ = commands.closed() => return Ok(()),
+    };
+    let Some(initial_message) = initial_message else {
+        return Err(anyhow::format_err!(
+            "close connection without sending initial message"
+        ));
+    };
+
+    // Only refuse after reading the initial message, as closing
+    // a connection with unread data would reset it instead
+    if state.bans.is_banned(peer_addr.ip()) {
+        return Err(refuse(&commands, "you are banned for now".to_owned()));
+    }
+    if let Message::User(user) | Message::Auth { user, .. } = &initial_message {
+        if let Some(violation) = state.limits.check_name(user) {
+            return Err(refuse(&commands, violation));
+        }
+    }
+    let mut user = match initial_message {
+        Message::User(user) if state.credentials.is_none() => user,
+        Message::User(_) => {
+            return Err(refuse(&commands, "authentication required".to_owned()));
+        }
+        Message::Auth { user, secret } => {
+            let ip = peer_addr.ip();
+            if !authenticate(&state, &user, secret).await? {
+                // Guessing secrets counts as violating the limits
+                let ban = Duration::from_secs(state.limits.ban_secs);
+                if state.bans.fail(ip, state.limits.max_violations, ban) {
+                    println!("[peer@{peer_addr}] banned for failing to authenticate");
+                }
+                return Err(refuse(&commands, "invalid username or secret".to_owned()));
+            }
+            state.bans.forgive(ip);
+            user
+        }
+        _ => {
+            return Err(anyhow::format_err!(
+                "initial message is neither User nor Auth: {initial_message:?}"
+            ));
+        }
+    };
+    if !state.users.insert(&user, commands.clone()) {
+        return Err(refuse(
+            &commands,
+            format!("username {user} is already taken"),
+        ));
+    }
+    println!("<{user}> joined chat");
+    state.peers.set_user(peer_addr, &user);
+    // Subscribe before announcing, so the client does not miss the
+    // announcement of its own arrival. Sending either fails only if the
+    // client is gone, which is noticed below. Returning early here would
+    // keep the name taken forever.
+    let _ = commands.send(Command::Enter(state.tx.subscribe()));
+    let _ = state.tx.send(Message::User(user.clone()));
+
+    let result = handle_messages(&mut messages, peer_addr, &state, &commands, &mut user).await;
+    state.users.remove(&user);
+    println!("<{user}> left chat");
+    // Sending only fails if nobody is listening, which is fine
+    let _ = state.tx.send(Message::Left(user));
+    result
+}
+
+/// Tell the client why it cannot be in the chat, returning the reason
+fn refuse(commands: &mpsc::UnboundedSender<Command>, error: String) -> anyhow::Error {
+    // The client may be gone already, which is fine
+    let _ = commands.send(Command::Send(Message::Error(error.clone())));
+    anyhow::format_err!(error)
+}
+
+/// Check the secret a user entered the chat with.
+/// Anyone is accepted if the server does not require authentication.
+async fn authenticate(state: &Arc<State>, user: &str, secret: String) -> Result<bool> {
+    // Only verify a few secrets at once, so clients cannot exhaust the
+    // blocking threads and memory by authenticating over and over
+    let _permit = state.auth_jobs.acquire().await?;
+    let state = state.clone();
+    let user = user.to_owned();
+    // Hashing the secret takes a while, so keep it off the async workers
+    let valid = task::spawn_blocking(move || match &state.credentials {
+        Some(credentials) => credentials.verify(&user, &secret),
+        None => true,
+    })
+    .await?;
+    Ok(valid)
+}
+
+/// Handle the messages a client sends after entering the chat as `user`
+async fn handle_messages(
+    messages: &mut (impl Stream<Item = Result<Message>> + Unpin),
+    peer_addr: SocketAddr,
+    state: &State,
+    commands: &mpsc::UnboundedSender<Command>,
+    user: &mut String,
+) -> Result<()> {
+    // The rooms this user is a member of
+    let mut joined = HashMap::new();
+    join_room(state, commands, &mut joined, DEFAULT_ROOM)?;
+    let mut bucket = TokenBucket::new(state.limits.rate, state.limits.burst);
+    let mut violations = 0;
+
+    loop {
+        let msg = select! {
+            msg = messages.next() => match msg.transpose()? {
+                Some(msg) => msg,
+                None => break,
+            },
+            // The outgoing half of the connection is gone, so stop reading
+            // as well. The partially read message does not matter anymore.
+            _ = commands.closed() => break,
+        };
+        count(&state.metrics.messages, 1);
+        if let Some(violation) = state.limits.check(&mut bucket, &msg) {
+            violations += 1;
+            if violations >= state.limits.max_violations {
+                let ban = Duration::from_secs(state.limits.ban_secs);
+                state.bans.ban(peer_addr.ip(), ban);
+                println!("<{user}> kicked for violating the limits");
+                return Err(refuse(commands, format!("kicked: {violation}")));
+            }
+            let error = format!("message dropped: {violation}");
+            commands.send(Command::Send(Message::Error(error)))?;
+            continue;
+        }
+        if run_plugins(state, commands, &joined, user, &msg)? {
+            continue;
+        }
+        match msg {
+            Message::User(new_user) if new_user == *user => {
+                // Nothing changes, ignore
+            }
+            Message::User(_) if state.credentials.is_some() => {
+                // The new name would not be backed by any credentials
+                let error = "usernames cannot be changed on this server".to_owned();
+                commands.send(Command::Send(Message::Error(error)))?;
+            }
+            Message::User(new_user) => {
+                // A user that already entered the chat wants to change their name
+                if state.users.rename(user, &new_user) {
+                    println!("<{user}> is now known as <{new_user}>");
+                    state.peers.set_user(peer_addr, &new_user);
+                    let from = std::mem::replace(user, new_user);
+                    state.tx.send(Message::Rename {
+                        from,
+                        to: user.clone(),
+                    })?;
+                } else {
+                    let error = format!("username {new_user} is already taken");
+                    commands.send(Command::Send(Message::Error(error)))?;
+                }
+            }
+            Message::ClientMessage(content) => post(state, &joined, user, &content),
+            Message::Join { room } => {
+                if join_room(state, commands, &mut joined, &room)? {
+                    println!("<{user}> joined room #{room}");
+                }
+                commands.send(Command::Send(Message::Join { room }))?;
+            }
+            Message::Leave { room } => {
+                if joined.remove(&room).is_some() {
+                    commands.send(Command::Unsubscribe(room.clone()))?;
+                    println!("<{user}> left room #{room}");
+                }
+                commands.send(Command::Send(Message::Leave { room }))?;
+            }
+            Message::Direct { to, content } => {
+                let delivered = state.users.get(&to).is_some_and(|recipient| {
+                    let msg = Message::DirectChat {
+                        user: user.clone(),
+                        content,
+                    };
+                    recipient.send(Command::Send(msg)).is_ok()
+                });
+                if !delivered {
+                    let msg = Message::Error(format!("user {to} is not online"));
+                    commands.send(Command::Send(msg))?;
+                }
+            }
+            Message::ListUsers => {
+                commands.send(Command::Send(Message::Users(state.users.list())))?;
+            }
+            Message::Auth { .. } => {
+                // Only meaningful as initial message, ignore
+            }
+            Message::Chat { .. }
+            | Message::DirectChat { .. }
+            | Message::Rename { .. }
+            | Message::Left(_)
+            | Message::Users(_)
+            | Message::Skipped(_)
+            | Message::ServerShutdown
+            | Message::Error(_) => {
+                // Client should not send this kind of message, ignore
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// Post a message from `user` to all rooms in `joined`
+fn post(
+    state: &State,
+    joined: &HashMap<String, broadcast::Sender<Message>>,
+    user: &str,
+    content: &str,
+) {
+    // Record and send while holding the lock, so users joining
+    // a room get each message either replayed or forwarded.
+    // Recording only queues the message for the log, so this is quick.
+    let mut history = state.history.lock().unwrap();
+    for (room, room_tx) in joined.iter() {
+        let msg = Message::Chat {
+            room: room.clone(),
+            user: user.to_owned(),
+            content: content.to_owned(),
+        };
+        if let Err(err) = history.record(&msg) {
+            eprintln!("cannot write to chat history: {err}");
+        }
+        // Sending only fails if nobody is listening, which is fine
+        let _ = room_tx.send(msg);
+    }
+}
+
+/// Let the plugins see a message `user` sent, and deliver their replies.
+/// Returns `true` if a plugin suppressed the message.
+fn run_plugins(
+    state: &State,
+    commands: &mpsc::UnboundedSender<Command>,
+    joined: &HashMap<String, broadcast::Sender<Message>>,
+    user: &str,
+    msg: &Message,
+) -> Result<bool> {
+    for plugin in &state.plugins {
+        let outcome = plugin.on_message(user, msg);
+        for reply in outcome.replies {
+            match reply {
+                Reply::Private(content) => {
+                    let user = plugin.name().to_owned();
+                    commands.send(Command::Send(Message::DirectChat { user, content }))?;
+                }
+                Reply::Public(content) => post(state, joined, plugin.name(), &content),
+            }
+        }
+        if outcome.suppress {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Make the user join `room`, replaying its recent messages to them.
+/// Returns `false` if they already were a member.
+fn join_room(
+    state: &State,
+    commands: &mpsc::UnboundedSender<Command>,
+    joined: &mut HashMap<String, broadcast::Sender<Message>>,
+    room: &str,
+) -> Result<bool> {
+    if joined.contains_key(room) {
+        return Ok(false);
+    }
+    let history = state.history.lock().unwrap();
+    let room_tx = state.rooms.get_or_create(room);
+    commands.send(Command::Subscribe {
+        room: room.to_owned(),
+        backlog: history.recent(room),
+        rx: room_tx.subscribe(),
+    })?;
+    joined.insert(room.to_owned(), room_tx);
+    Ok(true)
+}
+
+async fn handle_outgoing(
+    mut sink: impl Sink<Message, Error = anyhow::Error> + Unpin,
+    shutdown: broadcast::Receiver<Message>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    metrics: &Metrics,
+) -> Result<()> {
+    let mut shutdown = BroadcastStream::from(shutdown);
+    // Set once the client entered the chat
+    let mut announcements = None;
+    let mut rooms = StreamMap::new();
+    loop {
+        let msg = select! {
+            msg = shutdown.next() => match msg {
+                Some(Ok(Message::ServerShutdown)) => Message::ServerShutdown,
+                Some(_) => continue,
+                None => break,
+            },
+            Some(msg) = next_announcement(&mut announcements) => msg_or_skipped(msg, metrics),
+            Some((_, msg)) = rooms.next() => msg_or_skipped(msg, metrics),
+            command = commands.recv() => match command {
+                Some(Command::Enter(rx)) => {
+                    announcements = Some(BroadcastStream::from(rx));
+                    continue;
+                }
+                Some(Command::Subscribe { room, backlog, rx: room_rx }) => {
+                    for msg in backlog {
+                        sink.send(msg).await?;
+                    }
+                    rooms.insert(room, BroadcastStream::from(room_rx));
+                    continue;
+                }
+                Some(Command::Unsubscribe(room)) => {
+                    rooms.remove(&room);
+                    continue;
+                }
+                Some(Command::Send(msg)) => msg,
+                // The incoming half of the connection is gone
+                None => break,
+            },
+        };
+        let shutdown = matches!(msg, Message::ServerShutdown);
+        sink.send(msg).await?;
+        if shutdown {
+            break;
+        }
+    }
+    sink.close().await?;
+    Ok(())
+}
+
+/// The next announcement, or nothing while the client did not enter the chat
+async fn next_announcement(
+    announcements: &mut Option<BroadcastStream<Message>>,
+) -> Option<Result<Message, BroadcastStreamRecvError>> {
+    match announcements {
+        Some(announcements) => announcements.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tell a client that lags behind how many messages it missed,
+/// instead of dropping its connection
+fn msg_or_skipped(msg: Result<Message, BroadcastStreamRecvError>, metrics: &Metrics) -> Message {
+    match msg {
+        Ok(msg) => msg,
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            count(&metrics.lagged, 1);
+            count(&metrics.skipped, n);
+            Message::Skipped(n)
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Counters describing what the server is doing, and an HTTP endpoint
+//! exposing them in the Prometheus text format at `/metrics`,
+//! along with a JSON list of the connected peers at `/peers`
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{extract, http::header, response::IntoResponse, routing::get, Json, Router};
+use serde::Serialize;
+
+use super::state::State;
+
+/// Counters that only ever go up. Rates, like the number of messages
+/// per second, are derived from them by whoever scrapes the metrics.
+#[derive(Default)]
+pub struct Metrics {
+    /// Clients that connected since the server started
+    pub connections: AtomicU64,
+    /// Messages received from clients
+    pub messages: AtomicU64,
+    /// Times a client lagged behind and skipped messages
+    pub lagged: AtomicU64,
+    /// Messages skipped by lagging clients
+    pub skipped: AtomicU64,
+    /// Connections that ended with an error
+    pub errors: AtomicU64,
+}
+
+/// Add `n` to a counter
+pub fn count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Websocket,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    addr: SocketAddr,
+    transport: Transport,
+    /// The name of the user, once they entered the chat
+    user: Option<String>,
+    /// Seconds since the Unix epoch at which the peer connected
+    connected_at: u64,
+}
+
+/// Registry of all peers that are connected, whether they entered the chat or not
+#[derive(Default)]
+pub struct Peers {
+    peers: Mutex<HashMap<SocketAddr, Peer>>,
+}
+
+impl Peers {
+    pub fn insert(&self, addr: SocketAddr, transport: Transport) {
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let peer = Peer {
+            addr,
+            transport,
+            user: None,
+            connected_at,
+        };
+        self.peers.lock().unwrap().insert(addr, peer);
+    }
+
+    /// Record the name under which the peer at `addr` is in the chat
+    pub fn set_user(&self, addr: SocketAddr, user: &str) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+            peer.user = Some(user.to_owned());
+        }
+    }
+
+    pub fn remove(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+    }
+
+    /// All peers, the longest connected first
+    fn list(&self) -> Vec<Peer> {
+        let mut peers: Vec<_> = self.peers.lock().unwrap().values().cloned().collect();
+        peers.sort_by_key(|peer| (peer.connected_at, peer.addr));
+        peers
+    }
+
+    fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+}
+
+pub fn router(state: Arc<State>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/peers", get(peers))
+        .with_state(state)
+}
+
+async fn metrics(extract::State(state): extract::State<Arc<State>>) -> impl IntoResponse {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let metrics = &state.metrics;
+    let values = [
+        (
+            "chat_connections_total",
+            "counter",
+            "Clients that connected since the server started",
+            load(&metrics.connections),
+        ),
+        (
+            "chat_peers",
+            "gauge",
+            "Clients that are connected",
+            state.peers.len() as u64,
+        ),
+        (
+            "chat_users",
+            "gauge",
+            "Users that are in the chat",
+            state.users.list().len() as u64,
+        ),
+        (
+            "chat_messages_received_total",
+            "counter",
+            "Messages received from clients",
+            load(&metrics.messages),
+        ),
+        (
+            "chat_lagged_total",
+            "counter",
+            "Times a client lagged behind and skipped messages",
+            load(&metrics.lagged),
+        ),
+        (
+            "chat_skipped_messages_total",
+            "counter",
+            "Messages skipped by lagging clients",
+            load(&metrics.skipped),
+        ),
+        (
+            "chat_errors_total",
+            "counter",
+            "Connections that ended with an error",
+            load(&metrics.errors),
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, kind, help, value) in values {
+        writeln!(body, "# HELP {name} {help}").unwrap();
+        writeln!(body, "# TYPE {name} {kind}").unwrap();
+        writeln!(body, "{name} {value}").unwrap();
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+async fn peers(extract::State(state): extract::State<Arc<State>>) -> Json<Vec<Peer>> {
+    Json(state.peers.list())
+}
@@ -0,0 +1,300 @@
+//! State shared by all connections: the registries of rooms, users and
+//! bans, and the chat history
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::sync::{broadcast, mpsc, Notify, Semaphore};
+use tokio_rustls::TlsAcceptor;
+
+use super::{
+    admin::{Metrics, Peers},
+    Command, Limits,
+};
+use crate::{auth::Credentials, format::Format, plugin::ChatPlugin, Message};
+
+/// Registry of the broadcast channels of all chat rooms
+pub struct Rooms {
+    senders: Mutex<HashMap<String, broadcast::Sender<Message>>>,
+    /// The capacity of the channel of a new room
+    capacity: usize,
+}
+
+impl Rooms {
+    pub fn new(capacity: usize) -> Self {
+        Rooms {
+            senders: Mutex::default(),
+            capacity,
+        }
+    }
+
+    /// Get the sender of `room`, creating the room if it does not exist yet
+    pub fn get_or_create(&self, room: &str) -> broadcast::Sender<Message> {
+        let mut senders = self.senders.lock().unwrap();
+        // Forget about rooms nobody is listening to anymore
+        senders.retain(|_, tx| tx.receiver_count() > 0);
+        senders
+            .entry(room.to_owned())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .clone()
+    }
+}
+
+/// Registry of the connections of all users that are online,
+/// making sure no two users have the same name
+#[derive(Default)]
+pub struct Users {
+    connections: Mutex<HashMap<String, mpsc::UnboundedSender<Command>>>,
+}
+
+impl Users {
+    /// Register `commands` as the connection of `user`.
+    /// Returns `false` if the name is already taken.
+    pub fn insert(&self, user: &str, commands: mpsc::UnboundedSender<Command>) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(user) {
+            return false;
+        }
+        connections.insert(user.to_owned(), commands);
+        true
+    }
+
+    /// Register the connection of `from` under the name `to` instead.
+    /// Returns `false` if the name `to` is already taken.
+    pub fn rename(&self, from: &str, to: &str) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(to) {
+            return false;
+        }
+        if let Some(commands) = connections.remove(from) {
+            connections.insert(to.to_owned(), commands);
+        }
+        true
+    }
+
+    /// Unregister `user`, freeing up their name
+    pub fn remove(&self, user: &str) {
+        self.connections.lock().unwrap().remove(user);
+    }
+
+    /// The names of all users that are online, in alphabetical order
+    pub fn list(&self) -> Vec<String> {
+        let mut users: Vec<_> = self.connections.lock().unwrap().keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Get the connection of `user`, if they are online
+    pub fn get(&self, user: &str) -> Option<mpsc::UnboundedSender<Command>> {
+        self.connections.lock().unwrap().get(user).cloned()
+    }
+}
+
+/// Addresses of kicked clients, which may not reconnect for a while
+#[derive(Default)]
+pub struct Bans {
+    until: Mutex<HashMap<IpAddr, Instant>>,
+    /// The number of failed attempts to authenticate from each address
+    /// since it last succeeded or was banned
+    failures: Mutex<HashMap<IpAddr, u32>>,
+}
+
+impl Bans {
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.until
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut until = self.until.lock().unwrap();
+        // Forget about bans that expired
+        let now = Instant::now();
+        until.retain(|_, until| *until > now);
+        until.contains_key(&ip)
+    }
+
+    /// Count a failed attempt to authenticate from `ip`, banning it once
+    /// it failed `max` times. Returns `true` if it got banned.
+    pub fn fail(&self, ip: IpAddr, max: u32, duration: Duration) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(ip).or_default();
+        *count += 1;
+        if *count < max {
+            return false;
+        }
+        failures.remove(&ip);
+        self.ban(ip, duration);
+        true
+    }
+
+    /// Forget about the failed attempts to authenticate from `ip`
+    pub fn forgive(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+}
+
+/// Append-only log of all chat messages, stored as JSON lines.
+/// The most recent messages of a room are replayed to users joining it.
+pub struct History {
+    /// The lines to append to the log. They are written by a thread of
+    /// its own, so posting a message never waits for the disk.
+    log: Option<mpsc::UnboundedSender<String>>,
+    writer: Option<JoinHandle<()>>,
+    /// The most recent messages of each room
+    recent: HashMap<String, VecDeque<Message>>,
+    /// The number of messages to replay
+    replay: usize,
+}
+
+impl History {
+    /// Open the log at `path`, creating it if it does not exist yet
+    pub fn open(path: &Path, replay: usize) -> Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut history = History {
+            log: None,
+            writer: None,
+            recent: HashMap::new(),
+            replay,
+        };
+        for line in std::io::BufReader::new(&log).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(msg) => history.remember(msg),
+                Err(err) => eprintln!("skipping invalid message in chat history: {err}"),
+            }
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        history.log = Some(tx);
+        history.writer = Some(thread::spawn(move || write_log(log, rx)));
+        Ok(history)
+    }
+
+    /// Append a chat message to the log, in the background
+    pub fn record(&mut self, msg: &Message) -> Result<()> {
+        let line = serde_json::to_string(msg)?;
+        if let Some(log) = &self.log {
+            // The writer only stops once the log is dropped
+            let _ = log.send(line);
+        }
+        self.remember(msg.clone());
+        Ok(())
+    }
+
+    pub fn remember(&mut self, msg: Message) {
+        let Message::Chat { room, .. } = &msg else {
+            return;
+        };
+        let recent = self.recent.entry(room.clone()).or_default();
+        recent.push_back(msg);
+        while recent.len() > self.replay {
+            recent.pop_front();
+        }
+    }
+
+    /// The most recent messages of `room`, oldest first
+    pub fn recent(&self, room: &str) -> Vec<Message> {
+        self.recent
+            .get(room)
+            .map(|recent| recent.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Wait for the writer to append everything recorded, so the log is
+/// complete once the server is gone
+impl Drop for History {
+    fn drop(&mut self) {
+        self.log.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Append the lines received from `lines` to `log`, until all senders are dropped
+fn write_log(mut log: File, mut lines: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = lines.blocking_recv() {
+        if let Err(err) = writeln!(log, "{line}") {
+            eprintln!("cannot write to chat history: {err}");
+        }
+    }
+}
+
+/// Counts the websocket sessions. Axum runs them in tasks of its own,
+/// so shutting down has to wait for them separately.
+#[derive(Default)]
+pub struct Sessions {
+    open: Mutex<usize>,
+    /// Notified when the last session closes
+    closed: Notify,
+}
+
+impl Sessions {
+    /// Count a session as open until the returned guard is dropped
+    pub fn open(&self) -> Session<'_> {
+        *self.open.lock().unwrap() += 1;
+        Session(self)
+    }
+
+    /// Wait until no sessions are open
+    pub async fn all_closed(&self) {
+        loop {
+            // Created before checking, so closing in between is not missed
+            let closed = self.closed.notified();
+            if *self.open.lock().unwrap() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+/// An open websocket session, see [`Sessions::open`]
+pub struct Session<'a>(&'a Sessions);
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let mut open = self.0.open.lock().unwrap();
+        *open -= 1;
+        if *open == 0 {
+            self.0.closed.notify_waiters();
+        }
+    }
+}
+
+/// State shared by all connections
+pub struct State {
+    /// Channel for announcements to every connected client
+    pub tx: broadcast::Sender<Message>,
+    pub rooms: Rooms,
+    pub users: Users,
+    pub history: Mutex<History>,
+    /// The wire formats clients may use, in order of preference
+    pub formats: Vec<Format>,
+    /// Set if clients have to connect using TLS
+    pub tls: Option<TlsAcceptor>,
+    /// Set if clients have to authenticate
+    pub credentials: Option<Credentials>,
+    /// Permits to verify a secret, bounding the hashing done at once
+    pub auth_jobs: Semaphore,
+    pub limits: Limits,
+    pub bans: Bans,
+    pub plugins: Vec<Box<dyn ChatPlugin>>,
+    pub metrics: Metrics,
+    pub peers: Peers,
+    pub websockets: Sessions,
+}
@@ -0,0 +1,70 @@
+//! Loading of TLS configuration from PEM files
+
+use std::{fs::File, io, path::Path, sync::Arc};
+
+use tokio_rustls::{
+    rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+/// Create an acceptor of TLS connections, using the certificate chain
+/// and private key stored in the given PEM files
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Create a connector for TLS connections to servers with a certificate
+/// signed by one of the CA certificates stored in the given PEM file
+pub fn connector(ca_path: &Path) -> io::Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots.add(&cert).map_err(invalid_data)?;
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = io::BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = io::BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid_data(format!(
+        "no private key found in {}",
+        path.display()
+    )))
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use chat::Message;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use common::{connect, Server, TempDir};
+
+/// Get the body of the response to a GET request
+async fn http_get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "unexpected {head}");
+    body.to_owned()
+}
+
+#[tokio::test]
+async fn test_admin() {
+    let dir = TempDir::new("admin");
+    let mut server = Server::start(&dir, &["--admin-addr", "127.0.0.1:0"]);
+    let admin_addr = server.read_addr("admin endpoint listening on ");
+
+    let (mut read, mut write) = connect(&server.addr).await;
+    write
+        .write_message(&Message::User("alice".to_owned()))
+        .await
+        .unwrap();
+    write
+        .write_message(&Message::ClientMessage("hello".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::User(_))
+    ));
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Chat { .. })
+    ));
+
+    let metrics = http_get(&admin_addr, "/metrics").await;
+    for line in [
+        "# TYPE chat_connections_total counter",
+        "chat_connections_total 1",
+        "chat_peers 1",
+        "chat_users 1",
+        "chat_messages_received_total 1",
+        "chat_errors_total 0",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "{line:?} missing");
+    }
+
+    let peers: serde_json::Value =
+        serde_json::from_str(&http_get(&admin_addr, "/peers").await).unwrap();
+    let peers = peers.as_array().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0]["user"], "alice");
+    assert_eq!(peers[0]["transport"], "tcp");
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::fs;
+
+use chat::{auth::Credentials, Message};
+
+use common::{connect, Server, TempDir};
+
+fn auth(user: &str, secret: &str) -> Message {
+    Message::Auth {
+        user: user.to_owned(),
+        secret: secret.to_owned(),
+    }
+}
+
+/// Try to enter the chat with a secret, which must be refused.
+/// Returns the reason.
+async fn refused(addr: &str, user: &str, secret: &str) -> String {
+    let (mut read, mut write) = connect(addr).await;
+    write.write_message(&auth(user, secret)).await.unwrap();
+    let Some(Message::Error(error)) = read.read_message().await.unwrap() else {
+        panic!("{user} was not refused");
+    };
+    assert!(read.read_message().await.unwrap().is_none());
+    error
+}
+
+#[test]
+fn test_credentials() {
+    let dir = TempDir::new("credentials");
+    let path = dir.join("credentials");
+
+    let mut credentials = Credentials::default();
+    credentials.set("alice", "secret");
+    credentials.save(&path).unwrap();
+    let credentials = Credentials::load(&path).unwrap();
+    assert!(credentials.verify("alice", "secret"));
+    assert!(!credentials.verify("alice", "guess"));
+    assert!(!credentials.verify("bob", "secret"));
+
+    // Secrets are not stored in plain text
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with("alice:$argon2"));
+    assert!(!contents.contains("secret"));
+
+    fs::write(&path, "alice:secret\n").unwrap();
+    assert!(Credentials::load(&path).is_err());
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let dir = TempDir::new("auth");
+    let path = dir.join("credentials");
+    let mut credentials = Credentials::default();
+    credentials.set("alice", "secret");
+    credentials.save(&path).unwrap();
+    let server = Server::start(
+        &dir,
+        &[
+            "--credentials",
+            path.to_str().unwrap(),
+            "--max-violations=3",
+        ],
+    );
+
+    // Claiming a username is not enough
+    let (mut read, mut write) = connect(&server.addr).await;
+    write
+        .write_message(&Message::User("alice".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(_))
+    ));
+    assert!(read.read_message().await.unwrap().is_none());
+
+    // Unknown users are refused the same way
+    assert_eq!(
+        refused(&server.addr, "alice", "guess").await,
+        "invalid username or secret"
+    );
+    assert_eq!(
+        refused(&server.addr, "bob", "secret").await,
+        "invalid username or secret"
+    );
+
+    // Clients that did not enter the chat do not see who comes and goes
+    let (mut lurker_read, mut lurker_write) = connect(&server.addr).await;
+    let (mut read, mut write) = connect(&server.addr).await;
+    write.write_message(&auth("alice", "secret")).await.unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::User(user)) if user == "alice"
+    ));
+    lurker_write
+        .write_message(&auth("alice", "secret"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        lurker_read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("already taken")
+    ));
+    assert!(lurker_read.read_message().await.unwrap().is_none());
+
+    // Authenticated users cannot take another name
+    write
+        .write_message(&Message::User("mallory".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(_))
+    ));
+
+    // Guessing too often gets the client banned, even with the right secret
+    for _ in 0..3 {
+        refused(&server.addr, "alice", "guess").await;
+    }
+    assert_eq!(
+        refused(&server.addr, "alice", "secret").await,
+        "you are banned for now"
+    );
+}
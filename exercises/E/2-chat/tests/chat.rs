@@ -0,0 +1,149 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use chat::{format::Format, server::Server, Message};
+use futures::{channel::mpsc, future};
+use tokio::{
+    io::{self, DuplexStream},
+    sync::oneshot,
+};
+
+use common::{LocalServer, ScriptedClient, TempDir};
+
+fn chat(room: &str, user: &str, content: &str) -> Message {
+    Message::Chat {
+        room: room.to_owned(),
+        user: user.to_owned(),
+        content: content.to_owned(),
+    }
+}
+
+fn join(room: &str) -> Message {
+    Message::Join {
+        room: room.to_owned(),
+    }
+}
+
+fn leave(room: &str) -> Message {
+    Message::Leave {
+        room: room.to_owned(),
+    }
+}
+
+fn rename(from: &str, to: &str) -> Message {
+    Message::Rename {
+        from: from.to_owned(),
+        to: to.to_owned(),
+    }
+}
+
+#[tokio::test]
+async fn test_chat() {
+    let dir = TempDir::new("chat");
+    let server = LocalServer::start(&dir, &[]).await;
+
+    let mut alice = ScriptedClient::enter(server.addr, "alice", Format::Json);
+    alice.expect(&[Message::User("alice".to_owned())]).await;
+    let mut bob = ScriptedClient::enter(server.addr, "bob", Format::MessagePack);
+    bob.expect(&[Message::User("bob".to_owned())]).await;
+    alice.expect(&[Message::User("bob".to_owned())]).await;
+
+    // Everyone starts out in the lobby
+    alice.send(Message::ClientMessage("hi".to_owned()));
+    alice.expect(&[chat("lobby", "alice", "hi")]).await;
+    bob.expect(&[chat("lobby", "alice", "hi")]).await;
+
+    // Messages are posted to the rooms the sender is in only
+    bob.send(join("rust"));
+    bob.expect(&[join("rust")]).await;
+    bob.send(leave("lobby"));
+    bob.expect(&[leave("lobby")]).await;
+    bob.send(Message::ClientMessage("anyone here?".to_owned()));
+    bob.expect(&[chat("rust", "bob", "anyone here?")]).await;
+    alice.expect_silence().await;
+
+    // Joining a room again replays its recent messages before confirming
+    bob.send(join("lobby"));
+    bob.expect(&[chat("lobby", "alice", "hi"), join("lobby")])
+        .await;
+
+    bob.send(Message::Direct {
+        to: "alice".to_owned(),
+        content: "psst".to_owned(),
+    });
+    alice
+        .expect(&[Message::DirectChat {
+            user: "bob".to_owned(),
+            content: "psst".to_owned(),
+        }])
+        .await;
+    bob.expect_silence().await;
+    bob.send(Message::Direct {
+        to: "carol".to_owned(),
+        content: "psst".to_owned(),
+    });
+    bob.expect(&[Message::Error("user carol is not online".to_owned())])
+        .await;
+
+    alice.send(Message::ListUsers);
+    alice
+        .expect(&[Message::Users(vec!["alice".to_owned(), "bob".to_owned()])])
+        .await;
+
+    alice.send(Message::User("carol".to_owned()));
+    alice.expect(&[rename("alice", "carol")]).await;
+    bob.expect(&[rename("alice", "carol")]).await;
+    bob.send(Message::User("carol".to_owned()));
+    bob.expect(&[Message::Error("username carol is already taken".to_owned())])
+        .await;
+
+    drop(alice);
+    bob.expect(&[Message::Left("carol".to_owned())]).await;
+
+    server.shutdown().await;
+    bob.expect(&[Message::ServerShutdown]).await;
+}
+
+/// Open in-memory pipes to a server serving the other ends it receives
+/// from `connect`, with a port of their own for every pipe
+fn pipes(
+    connect: mpsc::UnboundedSender<io::Result<(DuplexStream, SocketAddr)>>,
+    port: u16,
+) -> impl FnMut() -> future::Ready<anyhow::Result<DuplexStream>> {
+    move || {
+        let (stream, server_end) = io::duplex(1024);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let sent = connect.unbounded_send(Ok((server_end, peer_addr)));
+        future::ready(sent.map(|()| stream).map_err(Into::into))
+    }
+}
+
+#[tokio::test]
+async fn test_serve_streams() {
+    let dir = TempDir::new("streams");
+    let server = Server::new(common::config(&dir, &[])).unwrap();
+    let (connect, incoming) = mpsc::unbounded();
+    let (shutdown, stop) = oneshot::channel::<()>();
+    let server = tokio::spawn(server.serve(incoming, async {
+        let _ = stop.await;
+        Ok(())
+    }));
+
+    let mut alice = ScriptedClient::enter_over(pipes(connect.clone(), 1), "alice", Format::Json);
+    alice.expect(&[Message::User("alice".to_owned())]).await;
+    let mut bob = ScriptedClient::enter_over(pipes(connect, 2), "bob", Format::Bincode);
+    bob.expect(&[Message::User("bob".to_owned())]).await;
+    alice.expect(&[Message::User("bob".to_owned())]).await;
+
+    bob.send(Message::ClientMessage("hello".to_owned()));
+    for client in [&mut alice, &mut bob] {
+        client.expect(&[chat("lobby", "bob", "hello")]).await;
+    }
+
+    shutdown.send(()).unwrap();
+    for client in [&mut alice, &mut bob] {
+        client.expect(&[Message::ServerShutdown]).await;
+    }
+    server.await.unwrap().unwrap();
+}
@@ -0,0 +1,257 @@
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    future::Future,
+    io::{BufRead, BufReader},
+    net::SocketAddr,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    time::Duration,
+};
+
+use chat::{
+    client::{self, Connector, Event},
+    codec::{MessageReader, MessageWriter},
+    format::{self, Format},
+    server::{self, Config},
+    Message,
+};
+use clap::Parser;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+
+/// How long to wait for a message before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How long nothing has to arrive for a client to be considered left alone
+const SILENCE: Duration = Duration::from_millis(200);
+
+/// A directory for the files of a test, which is removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory, unique to `name` and this test run
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("chat-test-{name}-{}", std::process::id()));
+        // Start from a clean slate, in case an earlier run left it behind
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A running server binary, which is killed when dropped
+pub struct Server {
+    process: Child,
+    pub addr: String,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Server {
+    pub fn start(dir: &Path, args: &[&str]) -> Self {
+        Self::start_at("127.0.0.1:0", dir, args)
+    }
+
+    /// Start a server listening on `addr`
+    pub fn start_at(addr: &str, dir: &Path, args: &[&str]) -> Self {
+        let mut process = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--addr", addr, "--history"])
+            .arg(dir.join("history.jsonl"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Error starting server");
+
+        let stdout = BufReader::new(process.stdout.take().unwrap());
+        let mut server = Server {
+            process,
+            addr: String::new(),
+            stdout,
+        };
+        server.addr = server.read_addr("listening on ");
+        server
+    }
+
+    /// Read the next line the server prints, which must report an address
+    /// after the given prefix
+    pub fn read_addr(&mut self, prefix: &str) -> String {
+        let mut line = String::new();
+        self.stdout.read_line(&mut line).unwrap();
+        line.trim()
+            .strip_prefix(prefix)
+            .expect("Server did not report its address")
+            .to_owned()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Connect to a server without TLS, using the JSON format
+pub async fn connect(
+    addr: &str,
+) -> (
+    MessageReader<ReadHalf<TcpStream>>,
+    MessageWriter<WriteHalf<TcpStream>>,
+) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let format = format::propose(&mut stream, Format::Json).await.unwrap();
+    let (read, write) = io::split(stream);
+    (
+        MessageReader::new(read, format),
+        MessageWriter::new(write, format),
+    )
+}
+
+/// The configuration of a server keeping its history in `dir`, with extra `args`
+pub fn config(dir: &Path, args: &[&str]) -> Config {
+    let history = dir.join("history.jsonl");
+    // Start from a clean slate, as the history is replayed to clients
+    let _ = std::fs::remove_file(&history);
+    let history = history.to_str().unwrap();
+    Config::parse_from(["server", "--history", history].iter().chain(args))
+}
+
+/// A server running within the test process, on an ephemeral port
+pub struct LocalServer {
+    pub addr: SocketAddr,
+    /// The address of the websocket gateway, if enabled
+    pub ws_addr: Option<SocketAddr>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl LocalServer {
+    pub async fn start(dir: &Path, args: &[&str]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server::Server::new(config(dir, args)).unwrap();
+        let ws_addr = server.ws_addr();
+        let (shutdown, stop) = oneshot::channel();
+        let task = tokio::spawn(server.run(listener, async {
+            let _ = stop.await;
+            Ok(())
+        }));
+        LocalServer {
+            addr,
+            ws_addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Shut the server down, and wait until all connections are closed
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        self.task.await.unwrap().unwrap();
+    }
+}
+
+/// A client driven by a test, going through the same connection logic
+/// as the client binary
+pub struct ScriptedClient {
+    pub user: String,
+    outgoing: mpsc::UnboundedSender<Message>,
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+impl ScriptedClient {
+    /// Enter the chat on the server at `addr` as `user`
+    pub fn enter(addr: SocketAddr, user: &str, format: Format) -> Self {
+        let connector = Connector::new(addr.to_string(), format);
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let handshake = Message::User(user.to_owned());
+        tokio::spawn(client::run(connector, handshake, outgoing_rx, events_tx));
+        ScriptedClient {
+            user: user.to_owned(),
+            outgoing,
+            events,
+        }
+    }
+
+    /// Enter the chat as `user` over the streams `connect` opens
+    pub fn enter_over<S, F>(
+        connect: impl FnMut() -> F + Send + 'static,
+        user: &str,
+        format: Format,
+    ) -> Self
+    where
+        F: Future<Output = anyhow::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let handshake = Message::User(user.to_owned());
+        tokio::spawn(client::run_over(
+            connect,
+            format,
+            handshake,
+            outgoing_rx,
+            events_tx,
+        ));
+        ScriptedClient {
+            user: user.to_owned(),
+            outgoing,
+            events,
+        }
+    }
+
+    pub fn send(&self, msg: Message) {
+        self.outgoing.send(msg).unwrap();
+    }
+
+    /// The next message from the server, skipping changes of the connection
+    async fn next_message(&mut self) -> Option<Message> {
+        loop {
+            match self.events.recv().await? {
+                Event::Message(msg) => return Some(msg),
+                Event::Status(_) => {}
+            }
+        }
+    }
+
+    /// Assert that the client receives exactly `expected`, in order
+    pub async fn expect(&mut self, expected: &[Message]) {
+        let mut received = Vec::new();
+        for _ in expected {
+            match time::timeout(TIMEOUT, self.next_message()).await {
+                Ok(Some(msg)) => received.push(msg),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        assert_eq!(received, expected, "messages received by {}", self.user);
+    }
+
+    /// Assert that the client receives nothing for a while
+    pub async fn expect_silence(&mut self) {
+        if let Ok(Some(msg)) = time::timeout(SILENCE, self.next_message()).await {
+            panic!("{} unexpectedly received {msg:?}", self.user);
+        }
+    }
+}
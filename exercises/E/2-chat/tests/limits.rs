@@ -0,0 +1,95 @@
+mod common;
+
+use chat::Message;
+
+use common::{connect, Server, TempDir};
+
+#[tokio::test]
+async fn test_limits() {
+    let dir = TempDir::new("limits");
+    let server = Server::start(
+        &dir,
+        &[
+            // Hardly any tokens are refilled during the test
+            "--rate=0.001",
+            "--burst=4",
+            "--max-content-len=10",
+            "--max-name-len=8",
+            "--max-violations=4",
+        ],
+    );
+
+    // Long names are refused right away
+    let (mut read, mut write) = connect(&server.addr).await;
+    write
+        .write_message(&Message::User("mallory the great".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("longer than 8 bytes")
+    ));
+    assert!(read.read_message().await.unwrap().is_none());
+
+    let (mut read, mut write) = connect(&server.addr).await;
+    write
+        .write_message(&Message::User("mallory".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::User(_))
+    ));
+
+    let send = |content: &str| Message::ClientMessage(content.to_owned());
+    write.write_message(&send("far too long")).await.unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("longer than 10 bytes")
+    ));
+    let join = Message::Join {
+        room: "far too long".to_owned(),
+    };
+    write.write_message(&join).await.unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("longer than 8 bytes")
+    ));
+    for _ in 0..2 {
+        write.write_message(&send("spam")).await.unwrap();
+        assert!(matches!(
+            read.read_message().await.unwrap(),
+            Some(Message::Chat { content, .. }) if content == "spam"
+        ));
+    }
+    write.write_message(&send("spam")).await.unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("too many messages")
+    ));
+
+    // The fourth violation gets the client kicked
+    write.write_message(&send("spam")).await.unwrap();
+    // The announcement that the client left may arrive first
+    let mut kicked = false;
+    while let Some(msg) = read.read_message().await.unwrap() {
+        match msg {
+            Message::Error(error) if error.starts_with("kicked") => kicked = true,
+            Message::Left(_) => {}
+            _ => panic!("unexpected {msg:?}"),
+        }
+    }
+    assert!(kicked);
+
+    // And banned from reconnecting
+    let (mut read, mut write) = connect(&server.addr).await;
+    write
+        .write_message(&Message::User("mallory".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Error(error)) if error.contains("banned")
+    ));
+    assert!(read.read_message().await.unwrap().is_none());
+}
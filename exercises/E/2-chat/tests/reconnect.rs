@@ -0,0 +1,54 @@
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Lines, Write},
+    process::{ChildStdout, Command, Stdio},
+};
+
+use common::{Server, TempDir};
+
+/// Read lines printed by the client until one starts with `prefix`
+fn expect_line(lines: &mut Lines<BufReader<ChildStdout>>, prefix: &str) -> String {
+    lines
+        .map(Result::unwrap)
+        .find(|line| line.starts_with(prefix))
+        .unwrap_or_else(|| panic!("Client did not print {prefix:?}"))
+}
+
+#[test]
+fn test_reconnect() {
+    let dir = TempDir::new("reconnect");
+    let server = Server::start(&dir, &[]);
+    let addr = server.addr.clone();
+
+    let mut client = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--addr", &addr])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Error starting client");
+    let mut stdin = client.stdin.take().unwrap();
+    let mut lines = BufReader::new(client.stdout.take().unwrap()).lines();
+    writeln!(stdin, "alice").unwrap();
+    expect_line(&mut lines, "Connected!");
+    expect_line(&mut lines, "<alice> joined the chat");
+    writeln!(stdin, "/join rust").unwrap();
+    expect_line(&mut lines, "You joined #rust");
+    writeln!(stdin, "/leave lobby").unwrap();
+    expect_line(&mut lines, "You left #lobby");
+
+    drop(server);
+    expect_line(&mut lines, "Disconnected");
+    // Lines typed while disconnected are sent after reconnecting
+    writeln!(stdin, "still there?").unwrap();
+
+    let _server = Server::start_at(&addr, &dir, &[]);
+    expect_line(&mut lines, "Connected!");
+    expect_line(&mut lines, "<alice> joined the chat");
+    // The client is back in the same rooms, and only in those
+    expect_line(&mut lines, "[#rust] <alice>: still there?");
+
+    drop(stdin);
+    assert!(lines.all(|line| !line.unwrap().starts_with("[#lobby]")));
+    assert!(client.wait().unwrap().success());
+}
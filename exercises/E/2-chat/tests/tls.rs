@@ -0,0 +1,58 @@
+mod common;
+
+use std::fs;
+
+use chat::{
+    codec::{MessageReader, MessageWriter},
+    format::{self, Format},
+    tls, Message,
+};
+use tokio::{io, net::TcpStream};
+use tokio_rustls::rustls::ServerName;
+
+use common::{Server, TempDir};
+
+#[tokio::test]
+async fn test_tls() {
+    let dir = TempDir::new("tls");
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let server = Server::start(
+        &dir,
+        &[
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    );
+
+    let stream = TcpStream::connect(&server.addr).await.unwrap();
+    let mut stream = tls::connector(&cert_path)
+        .unwrap()
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .expect("Error during TLS handshake");
+    let format = format::propose(&mut stream, Format::Json).await.unwrap();
+    let (read, write) = io::split(stream);
+    let (mut read, mut write) = (
+        MessageReader::new(read, format),
+        MessageWriter::new(write, format),
+    );
+
+    write
+        .write_message(&Message::User("alice".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::User(user)) if user == "alice"
+    ));
+
+    // Plain TCP clients are not understood
+    let mut stream = TcpStream::connect(&server.addr).await.unwrap();
+    assert!(format::propose(&mut stream, Format::Json).await.is_err());
+}
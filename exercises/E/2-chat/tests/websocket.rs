@@ -0,0 +1,108 @@
+mod common;
+
+use chat::{
+    codec::{MessageReader, MessageWriter},
+    format::{self, Format},
+    Message, DEFAULT_ROOM,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{io, net::TcpStream};
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+use common::{LocalServer, Server, TempDir};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn ws_send(ws: &mut WebSocket, msg: &Message) {
+    let text = serde_json::to_string(msg).unwrap();
+    ws.send(tungstenite::Message::Text(text)).await.unwrap();
+}
+
+/// Receive the next chat message, skipping other frames
+async fn ws_recv(ws: &mut WebSocket) -> Message {
+    loop {
+        if let tungstenite::Message::Text(text) = ws.next().await.unwrap().unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_websocket() {
+    let dir = TempDir::new("ws");
+    let mut server = Server::start(&dir, &["--ws-addr", "127.0.0.1:0"]);
+    let ws_addr = server.read_addr("websocket gateway listening on ");
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{ws_addr}/ws"))
+        .await
+        .expect("Error connecting to the websocket gateway");
+    // Frames other than text frames are ignored by the server
+    ws.send(tungstenite::Message::Ping(Vec::new()))
+        .await
+        .unwrap();
+    ws_send(&mut ws, &Message::User("alice".to_owned())).await;
+    assert!(matches!(ws_recv(&mut ws).await, Message::User(user) if user == "alice"));
+
+    let mut stream = TcpStream::connect(&server.addr).await.unwrap();
+    let format = format::propose(&mut stream, Format::MessagePack)
+        .await
+        .unwrap();
+    let (read, write) = io::split(stream);
+    let (mut read, mut write) = (
+        MessageReader::new(read, format),
+        MessageWriter::new(write, format),
+    );
+    write
+        .write_message(&Message::User("bob".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::User(user)) if user == "bob"
+    ));
+    assert!(matches!(ws_recv(&mut ws).await, Message::User(user) if user == "bob"));
+
+    // Websocket and TCP clients chat with each other
+    write
+        .write_message(&Message::ClientMessage("hi alice".to_owned()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        ws_recv(&mut ws).await,
+        Message::Chat { room, user, content }
+            if room == DEFAULT_ROOM && user == "bob" && content == "hi alice"
+    ));
+    ws_send(&mut ws, &Message::ClientMessage("hi bob".to_owned())).await;
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Chat { user, content, .. })
+            if user == "bob" && content == "hi alice"
+    ));
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Chat { room, user, content })
+            if room == DEFAULT_ROOM && user == "alice" && content == "hi bob"
+    ));
+
+    ws.close(None).await.unwrap();
+    assert!(matches!(
+        read.read_message().await.unwrap(),
+        Some(Message::Left(user)) if user == "alice"
+    ));
+}
+
+#[tokio::test]
+async fn test_websocket_shutdown() {
+    let dir = TempDir::new("ws-shutdown");
+    let server = LocalServer::start(&dir, &["--ws-addr", "127.0.0.1:0"]).await;
+    let ws_addr = server.ws_addr.unwrap();
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{ws_addr}/ws"))
+        .await
+        .unwrap();
+    ws_send(&mut ws, &Message::User("alice".to_owned())).await;
+    assert!(matches!(ws_recv(&mut ws).await, Message::User(user) if user == "alice"));
+
+    // Shutting down waits for websocket clients to be told as well
+    server.shutdown().await;
+    assert!(matches!(ws_recv(&mut ws).await, Message::ServerShutdown));
+}